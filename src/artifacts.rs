@@ -0,0 +1,177 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+use crate::json;
+
+/// Cargo subcommands that produce binaries without running them
+const BUILD_COMMANDS: &[&str] = &["build", "b", "rustc"];
+/// Cargo subcommands that build binaries only when given `--no-run`
+const NO_RUN_COMMANDS: &[&str] = &["test", "t", "bench"];
+
+/// A binary or cdylib produced by a build
+#[derive(Debug, Clone)]
+pub struct Artifact {
+    /// Directory of the crate that produced this, from the manifest path of the record
+    pub project: PathBuf,
+    /// Target kind, e.g. `bin` or `cdylib`
+    pub kind: String,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Make sure the command is a cargo invocation that builds something
+pub fn check_command(external: bool, args: &[&str]) -> Result<()> {
+    if external {
+        bail!("--artifact-sizes can only be used with cargo commands");
+    }
+    match args.first() {
+        Some(c) if BUILD_COMMANDS.contains(c) => Ok(()),
+        Some(c) if NO_RUN_COMMANDS.contains(c) && args.contains(&"--no-run") => Ok(()),
+        _ => bail!("--artifact-sizes requires a build command, e.g. `build` or `test --no-run`"),
+    }
+}
+
+/// Collect binaries and cdylibs from the `compiler-artifact` records of cargo's JSON output
+pub fn collect(stdout: &[u8]) -> Vec<Artifact> {
+    let mut result = Vec::new();
+    for line in String::from_utf8_lossy(stdout).lines() {
+        let record = match json::parse(line) {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
+        if record.get("reason").and_then(json::Value::as_str) != Some("compiler-artifact") {
+            continue;
+        }
+
+        let project = match record.get("manifest_path").and_then(json::Value::as_str) {
+            Some(manifest) => Path::new(manifest)
+                .parent()
+                .unwrap_or_else(|| Path::new(""))
+                .to_owned(),
+            None => continue,
+        };
+        let kinds: Vec<&str> = record
+            .get("target")
+            .and_then(|t| t.get("kind"))
+            .and_then(json::Value::as_array)
+            .unwrap_or(&[])
+            .iter()
+            .filter_map(json::Value::as_str)
+            .collect();
+
+        let files: Vec<&str> = if kinds.contains(&"cdylib") {
+            record
+                .get("filenames")
+                .and_then(json::Value::as_array)
+                .unwrap_or(&[])
+                .iter()
+                .filter_map(json::Value::as_str)
+                .collect()
+        } else {
+            record
+                .get("executable")
+                .and_then(json::Value::as_str)
+                .into_iter()
+                .collect()
+        };
+
+        for file in files {
+            let path = PathBuf::from(file);
+            if let Ok(meta) = path.metadata() {
+                result.push(Artifact {
+                    project: project.clone(),
+                    kind: kinds.join(","),
+                    path,
+                    size: meta.len(),
+                });
+            }
+        }
+    }
+    result
+}
+
+/// Sort order of the artifact table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortBy {
+    /// Largest first
+    Size,
+    /// Alphabetical by file name
+    Name,
+}
+
+/// Print a per-project table of artifacts and sizes, and the overall total
+pub fn print_table(artifacts: &[Artifact], sort_by: SortBy, json_format: bool) {
+    let mut projects: BTreeMap<&Path, Vec<&Artifact>> = BTreeMap::new();
+    let mut seen = HashSet::new();
+    for a in artifacts {
+        // The same file is reported by every run that built it
+        if seen.insert(&a.path) {
+            projects.entry(&a.project).or_default().push(a);
+        }
+    }
+    for list in projects.values_mut() {
+        match sort_by {
+            SortBy::Size => list.sort_by_key(|a| std::cmp::Reverse(a.size)),
+            SortBy::Name => list.sort_by(|a, b| a.path.file_name().cmp(&b.path.file_name())),
+        }
+    }
+    let total: u64 = projects.values().flatten().map(|a| a.size).sum();
+
+    if json_format {
+        let artifact_json = |a: &Artifact| {
+            json::Value::object(vec![
+                ("kind", a.kind.as_str().into()),
+                ("path", a.path.to_string_lossy().into_owned().into()),
+                ("size", a.size.into()),
+            ])
+        };
+        let project_json = |(project, list): (&&Path, &Vec<&Artifact>)| {
+            json::Value::object(vec![
+                ("project", project.to_string_lossy().into_owned().into()),
+                (
+                    "artifacts",
+                    json::Value::Array(list.iter().map(|a| artifact_json(a)).collect()),
+                ),
+                ("total", list.iter().map(|a| a.size).sum::<u64>().into()),
+            ])
+        };
+        let value = json::Value::object(vec![
+            (
+                "projects",
+                json::Value::Array(projects.iter().map(project_json).collect()),
+            ),
+            ("total", total.into()),
+        ]);
+        println!("{}", value);
+        return;
+    }
+
+    for (project, list) in &projects {
+        println!("{}", project.display());
+        for a in list {
+            let name = a.path.file_name().unwrap_or_default().to_string_lossy();
+            println!("  {:>12}  {:<8} {}", format_size(a.size), a.kind, name);
+        }
+        let subtotal = list.iter().map(|a| a.size).sum();
+        println!("  {:>12}  total", format_size(subtotal));
+    }
+    println!("{:>14}  overall total", format_size(total));
+}
+
+/// Human-readable size
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
@@ -0,0 +1,147 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::scan;
+
+/// How to decide whether a crate needs the command to be run again
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecursiveMode {
+    /// Run in every crate
+    AlwaysRun,
+    /// Skip crates with no sources modified after the last successful run
+    TrustTimestamps,
+    /// Skip crates whose sources hash to the same value as on the last successful run
+    ChecksumBased,
+}
+
+/// State of the crates from earlier runs, one file per crate.
+/// For timestamps the file has the start time of the last successful run,
+/// for checksums the hash of the sources.
+#[derive(Debug)]
+pub struct StateCache {
+    pub dir: PathBuf,
+    pub mode: RecursiveMode,
+}
+impl StateCache {
+    /// State file of the crate in `dir`, named after its absolute path
+    fn file(&self, dir: &Path) -> Result<PathBuf> {
+        let name: String = scan::absolute(dir)?
+            .to_string_lossy()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let extension = match self.mode {
+            RecursiveMode::TrustTimestamps => "timestamp",
+            _ => "hash",
+        };
+        Ok(self.dir.join(format!("{}.{}", name, extension)))
+    }
+
+    /// Returns `None` if the crate in `dir` is unchanged since its last successful run,
+    /// otherwise the state to `store` if the run that `started` now succeeds
+    pub fn check(&self, dir: &Path, started: SystemTime) -> Result<Option<String>> {
+        let stored = fs::read_to_string(self.file(dir)?).ok();
+        let stored = stored.as_deref().map(str::trim);
+        match self.mode {
+            RecursiveMode::AlwaysRun => Ok(Some(String::new())),
+            RecursiveMode::TrustTimestamps => {
+                let newest = source_files(dir)?
+                    .iter()
+                    .filter_map(|f| dir.join(f).metadata().and_then(|m| m.modified()).ok())
+                    .max()
+                    .map(seconds)
+                    .unwrap_or(0);
+                // Require a later second, a file modified in the same second may have been missed
+                if stored.and_then(|s| s.parse::<u64>().ok()) > Some(newest) {
+                    Ok(None)
+                } else {
+                    Ok(Some(seconds(started).to_string()))
+                }
+            }
+            RecursiveMode::ChecksumBased => {
+                let hash = source_hash(dir)?;
+                if stored == Some(hash.as_str()) {
+                    Ok(None)
+                } else {
+                    Ok(Some(hash))
+                }
+            }
+        }
+    }
+
+    pub fn store(&self, dir: &Path, state: &str) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("creating cache directory {:?}", self.dir))?;
+        let file = self.file(dir)?;
+        fs::write(&file, state).with_context(|| format!("writing {:?}", file))
+    }
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// `Cargo.toml` and all `.rs` files of the crate in `dir`, relative to it and sorted.
+/// The `target` directory and nested crates are not included.
+fn source_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![PathBuf::from("Cargo.toml")];
+    collect_sources(dir, Path::new(""), &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+/// Hash of the source files of the crate in `dir`
+fn source_hash(dir: &Path) -> Result<String> {
+    let mut hash = Fnv::new();
+    for file in &source_files(dir)? {
+        let path = dir.join(file);
+        let content = fs::read(&path).with_context(|| format!("reading {:?}", path))?;
+        hash.write(file.to_string_lossy().as_bytes());
+        hash.write(&[0]);
+        hash.write(&(content.len() as u64).to_le_bytes());
+        hash.write(&content);
+    }
+    Ok(format!("{:016x}", hash.0))
+}
+
+/// Add the `.rs` files under `dir.join(relative)` to `files`, relative to `dir`
+fn collect_sources(dir: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let current = dir.join(relative);
+    for e in current
+        .read_dir()
+        .with_context(|| format!("reading directory {:?}", current))?
+    {
+        let e = e?;
+        let path = relative.join(e.file_name());
+        let file_type = e.file_type()?;
+        if file_type.is_dir() {
+            let nested_crate = dir.join(&path).join("Cargo.toml").exists();
+            if e.file_name() != "target" && !nested_crate {
+                collect_sources(dir, &path, files)?;
+            }
+        } else if path.extension().is_some_and(|ext| ext == "rs") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// 64-bit FNV-1a, stable across runs and Rust versions unlike `DefaultHasher`
+struct Fnv(u64);
+impl Fnv {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= u64::from(b);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use toml_edit::{DocumentMut, Value};
+
+/// Global configuration directory: `$XDG_CONFIG_HOME/cargo-recursive`, or
+/// `~/.config/cargo-recursive`, falling back to `~/.cargo-recursive` if only that exists
+pub fn home_dir() -> Option<PathBuf> {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let xdg = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => home.as_ref()?.join(".config"),
+    }
+    .join("cargo-recursive");
+    match home.map(|h| h.join(".cargo-recursive")) {
+        Some(legacy) if !xdg.exists() && legacy.exists() => Some(legacy),
+        _ => Some(xdg),
+    }
+}
+
+/// Default flags from `config.toml` in `home`, as command line arguments.
+/// Keys are the long flag names, e.g. `depth = 3` or `exclude-if-file-exists = [".skip"]`.
+/// Keys for which `skip` returns true, given the key and its arguments, are left out,
+/// e.g. flags already given on the command line or conflicting with them.
+pub fn default_args(home: &Path, skip: impl Fn(&str, &[String]) -> bool) -> Result<Vec<String>> {
+    let path = home.join("config.toml");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let text = fs::read_to_string(&path).with_context(|| format!("reading {:?}", path))?;
+    let doc: DocumentMut = text
+        .parse()
+        .with_context(|| format!("parsing {:?}", path))?;
+
+    let mut args = Vec::new();
+    for (key, item) in doc.iter() {
+        let mut key_args = Vec::new();
+        let values = match item.as_value() {
+            Some(Value::Array(items)) => items.iter().collect(),
+            Some(value) => vec![value],
+            None => bail!("{:?}: unsupported value for {:?}", path, key),
+        };
+        for value in values {
+            match value {
+                Value::Boolean(b) if *b.value() => key_args.push(format!("--{}", key)),
+                Value::Boolean(_) => {}
+                Value::String(s) => key_args.push(format!("--{}={}", key, s.value())),
+                Value::Integer(i) => key_args.push(format!("--{}={}", key, i.value())),
+                Value::Float(f) => key_args.push(format!("--{}={}", key, f.value())),
+                _ => bail!("{:?}: unsupported value for {:?}", path, key),
+            }
+        }
+        if !skip(key, &key_args) {
+            args.extend(key_args);
+        }
+    }
+    Ok(args)
+}
+
+/// Patterns of directories not to search, from the `ignore` file in `home`.
+/// One per line, empty lines and lines starting with `#` are skipped.
+pub fn ignore_patterns(home: &Path) -> Result<Vec<String>> {
+    let path = home.join("ignore");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let text = fs::read_to_string(&path).with_context(|| format!("reading {:?}", path))?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Patterns of crates to run in, from the colon separated `CARGO_RECURSIVE_INCLUDE` variable
+pub fn include_patterns() -> Vec<String> {
+    env::var("CARGO_RECURSIVE_INCLUDE")
+        .unwrap_or_default()
+        .split(':')
+        .filter(|pattern| !pattern.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Match `text` against a glob `pattern`, where `*` matches any run of characters
+/// and `?` any single character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it was tried at
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
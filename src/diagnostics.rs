@@ -0,0 +1,49 @@
+use crate::json;
+
+/// Compiler messages from cargo's JSON output
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    pub errors: usize,
+    pub warnings: usize,
+    /// Human readable form of the messages, as the compiler would print them
+    pub rendered: String,
+}
+impl Diagnostics {
+    /// Short description of the counts, e.g. `2 errors, 1 warning`
+    pub fn describe(&self) -> String {
+        let plural =
+            |n: usize, word: &str| format!("{} {}{}", n, word, if n == 1 { "" } else { "s" });
+        format!(
+            "{}, {}",
+            plural(self.errors, "error"),
+            plural(self.warnings, "warning")
+        )
+    }
+}
+
+/// Collect errors and warnings from the `compiler-message` records of cargo's JSON output
+pub fn collect(stdout: &[u8]) -> Diagnostics {
+    let mut result = Diagnostics::default();
+    for line in String::from_utf8_lossy(stdout).lines() {
+        let record = match json::parse(line) {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
+        if record.get("reason").and_then(json::Value::as_str) != Some("compiler-message") {
+            continue;
+        }
+        let message = match record.get("message") {
+            Some(message) => message,
+            None => continue,
+        };
+        match message.get("level").and_then(json::Value::as_str) {
+            Some("error") => result.errors += 1,
+            Some("warning") => result.warnings += 1,
+            _ => continue,
+        }
+        if let Some(rendered) = message.get("rendered").and_then(json::Value::as_str) {
+            result.rendered += rendered;
+        }
+    }
+    result
+}
@@ -0,0 +1,387 @@
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
+
+/// Minimal JSON value, enough for the machine-readable outputs of this tool
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// Keys are kept in insertion order
+    Object(Vec<(String, Value)>),
+}
+impl Value {
+    /// Build an object from key-value pairs
+    pub fn object<K: Into<String>>(pairs: Vec<(K, Value)>) -> Self {
+        Value::Object(pairs.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(pairs) => pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+impl From<bool> for Value {
+    fn from(v: bool) -> Self {
+        Value::Bool(v)
+    }
+}
+impl From<usize> for Value {
+    fn from(v: usize) -> Self {
+        Value::Number(v as f64)
+    }
+}
+impl From<u64> for Value {
+    fn from(v: u64) -> Self {
+        Value::Number(v as f64)
+    }
+}
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Value::String(v.to_owned())
+    }
+}
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Value::String(v)
+    }
+}
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        v.map(Into::into).unwrap_or(Value::Null)
+    }
+}
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write_string(f, s),
+            Value::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(pairs) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Parse a JSON document
+pub fn parse(text: &str) -> Result<Value> {
+    let mut p = Parser {
+        bytes: text.as_bytes(),
+        pos: 0,
+    };
+    let value = p.value()?;
+    p.skip_whitespace();
+    if p.pos != p.bytes.len() {
+        bail!("trailing characters at offset {}", p.pos);
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(
+            self.peek(),
+            Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r')
+        ) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<()> {
+        self.skip_whitespace();
+        if self.peek() != Some(c) {
+            bail!("expected {:?} at offset {}", c as char, self.pos);
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value> {
+        if !self.bytes[self.pos..].starts_with(word.as_bytes()) {
+            bail!("invalid literal at offset {}", self.pos);
+        }
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'"') => Ok(Value::String(self.string()?)),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => {
+                            self.expect(b']')?;
+                            return Ok(Value::Array(items));
+                        }
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut pairs = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Value::Object(pairs));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    pairs.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => {
+                            self.expect(b'}')?;
+                            return Ok(Value::Object(pairs));
+                        }
+                    }
+                }
+            }
+            Some(c) if c == b'-' || c.is_ascii_digit() => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_digit() || b"+-.eE".contains(&c))
+                {
+                    self.pos += 1;
+                }
+                let raw = std::str::from_utf8(&self.bytes[start..self.pos])?;
+                Ok(Value::Number(
+                    raw.parse()
+                        .with_context(|| format!("invalid number {:?}", raw))?,
+                ))
+            }
+            Some(c) => bail!("unexpected {:?} at offset {}", c as char, self.pos),
+            None => bail!("unexpected end of input"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        if self.peek() != Some(b'"') {
+            bail!("expected a string at offset {}", self.pos);
+        }
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let start = self.pos;
+            while !matches!(self.peek(), None | Some(b'"') | Some(b'\\')) {
+                self.pos += 1;
+            }
+            s.push_str(std::str::from_utf8(&self.bytes[start..self.pos])?);
+            match self.peek() {
+                None => bail!("unterminated string"),
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(s);
+                }
+                _ => {
+                    self.pos += 1;
+                    let c = self.peek().context("unterminated string")?;
+                    self.pos += 1;
+                    match c {
+                        b'n' => s.push('\n'),
+                        b't' => s.push('\t'),
+                        b'r' => s.push('\r'),
+                        b'b' => s.push('\u{8}'),
+                        b'f' => s.push('\u{c}'),
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.bytes[self.pos..].starts_with(b"\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    bail!("invalid surrogate pair at offset {}", self.pos);
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            s.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                        }
+                        c => s.push(c as char),
+                    }
+                }
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let hex = self
+            .bytes
+            .get(self.pos..self.pos + 4)
+            .context("truncated escape")?;
+        self.pos += 4;
+        Ok(u32::from_str_radix(std::str::from_utf8(hex)?, 16)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_escapes() {
+        let value = parse(r#""a\"b\\c\/d\n\t\r\b\fé""#).unwrap();
+        assert_eq!(value.as_str(), Some("a\"b\\c/d\n\t\r\u{8}\u{c}é"));
+    }
+
+    #[test]
+    fn parse_surrogate_pairs() {
+        assert_eq!(parse(r#""\ud83e\udd80""#).unwrap().as_str(), Some("🦀"));
+        assert_eq!(parse(r#""\u00e9\u20ac""#).unwrap().as_str(), Some("é€"));
+        assert_eq!(parse(r#""\ud83eA""#).unwrap().as_str(), Some("\u{fffd}A"));
+        assert!(parse(r#""\ud83e\u0041""#).is_err());
+    }
+
+    #[test]
+    fn parse_numbers() {
+        assert_eq!(parse("0").unwrap(), Value::Number(0.0));
+        assert_eq!(parse("-12").unwrap(), Value::Number(-12.0));
+        assert_eq!(parse("1.5e3").unwrap(), Value::Number(1500.0));
+        assert_eq!(parse("2E-2").unwrap(), Value::Number(0.02));
+        assert!(parse("1-2").is_err());
+        assert!(parse("-").is_err());
+    }
+
+    #[test]
+    fn parse_nesting() {
+        let value = parse(r#" { "a" : [1, {"b": [true, false, null]}, []], "c": {} } "#).unwrap();
+        assert_eq!(
+            value,
+            Value::object(vec![
+                (
+                    "a",
+                    Value::Array(vec![
+                        Value::Number(1.0),
+                        Value::object(vec![(
+                            "b",
+                            Value::Array(vec![Value::Bool(true), Value::Bool(false), Value::Null])
+                        )]),
+                        Value::Array(Vec::new()),
+                    ])
+                ),
+                ("c", Value::Object(Vec::new())),
+            ])
+        );
+        assert_eq!(
+            value.get("a").and_then(Value::as_array).map(|a| a.len()),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn parse_rejects_invalid_input() {
+        assert!(parse(r#"{"a": 1} x"#).is_err());
+        assert!(parse("[1, 2]]").is_err());
+        assert!(parse("[1, 2").is_err());
+        assert!(parse(r#"{"a" 1}"#).is_err());
+        assert!(parse(r#""unterminated"#).is_err());
+        assert!(parse("nul").is_err());
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let value = Value::object(vec![
+            (
+                "string",
+                Value::from("quote \" backslash \\ newline \n tab \t bell \u{7} 🦀"),
+            ),
+            ("integer", Value::from(42u64)),
+            ("float", Value::Number(-0.25)),
+            ("bool", Value::from(true)),
+            ("null", Value::from(None::<&str>)),
+            (
+                "array",
+                Value::Array(vec![Value::from(1usize), Value::from("x")]),
+            ),
+            (
+                "object",
+                Value::object(vec![("nested", Value::Array(Vec::new()))]),
+            ),
+        ]);
+        let text = value.to_string();
+        assert_eq!(parse(&text).unwrap(), value);
+        assert!(text.contains(r#""integer":42"#));
+        assert!(text.contains(r"bell \u0007"));
+    }
+}
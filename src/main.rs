@@ -1,28 +1,77 @@
-use std::env::{args, current_dir};
-use std::io::{self, Write};
+use std::collections::BTreeMap;
+use std::env::{self, args, current_dir};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::{exit, Command};
+use std::process::{exit, Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::{bail, Context, Result};
-use clap::{App, Arg};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{App, Arg, ErrorKind};
+
+mod artifacts;
+mod cache;
+mod config;
+mod diagnostics;
+mod json;
+mod makefile;
+mod manifest;
+mod memory;
+mod output;
+mod patch;
+mod report;
+mod scan;
+
+use cache::{RecursiveMode, StateCache};
+use manifest::Manifest;
+use memory::MemoryUsage;
+use output::{LogGroups, Printer};
+use patch::FieldUpdate;
+use report::{Report, Status};
+use scan::Scanner;
+
+/// Prefix of fatal errors and indent of their causes, set by `--error-prefix` and `--error-chain-indent`
+static ERROR_FORMAT: OnceLock<(String, String)> = OnceLock::new();
 
 fn main() {
     if let Err(e) = actual_main() {
-        eprintln!("Error: {}", e);
+        let (prefix, indent) = error_format();
+        eprintln!("{}{}", prefix, e);
         for c in e.chain().skip(1) {
-            eprintln!("    {}", c);
+            eprintln!("{}{}", indent, c);
         }
         exit(1);
     }
 }
 
+fn error_format() -> (&'static str, &'static str) {
+    match ERROR_FORMAT.get() {
+        Some((prefix, indent)) => (prefix, indent),
+        None => ("Error: ", "    "),
+    }
+}
+
+/// Print a non-fatal error
+fn warn(e: &anyhow::Error) {
+    let (_, indent) = error_format();
+    eprintln!("Warn: {}", e);
+    for c in e.chain().skip(1) {
+        eprintln!("{}{}", indent, c);
+    }
+}
+
 fn actual_main() -> Result<()> {
     let mut args: Vec<String> = args().collect();
     if args.len() >= 2 && &args[1] == "recursive" {
         args.remove(1);
     }
 
-    let matches = App::new("cargo recursive")
+    // Arguments are named after their long flags, which are the keys of config.toml
+    let app = App::new("cargo recursive")
         .bin_name("cargo recursive")
         .arg(
             Arg::with_name("depth")
@@ -30,12 +79,50 @@ fn actual_main() -> Result<()> {
                 .default_value("64")
                 .help("Max depth to search into"),
         )
+        .arg(
+            Arg::with_name("depth-from-workspace-root")
+                .long("depth-from-workspace-root")
+                .help("Count --depth from the root of the workspace containing the target directory"),
+        )
         .arg(
             Arg::with_name("path")
                 .short("p")
                 .long("path")
                 .help("Target directory"),
         )
+        .arg(
+            Arg::with_name("external-resolver")
+                .long("external-resolver")
+                .takes_value(true)
+                .value_name("COMMAND")
+                .help("Shell command printing the directories to run in, one per line, instead of searching for them"),
+        )
+        .arg(
+            Arg::with_name("sort-by")
+                .long("sort-by")
+                .takes_value(true)
+                .possible_values(&["name", "path", "depth", "mod-time"])
+                .help("Order of the directories instead of the order they are found in, mod-time is most recent first"),
+        )
+        .arg(
+            Arg::with_name("reverse")
+                .long("reverse")
+                .help("Reverse the order of the directories"),
+        )
+        .arg(
+            Arg::with_name("skip-after")
+                .long("skip-after")
+                .takes_value(true)
+                .value_name("N")
+                .help("Skip the first N directories, with --stop-after this pages through them"),
+        )
+        .arg(
+            Arg::with_name("stop-after")
+                .long("stop-after")
+                .takes_value(true)
+                .value_name("N")
+                .help("Only process the first N directories"),
+        )
         .arg(
             Arg::with_name("dry-run")
                 .short("d")
@@ -55,23 +142,598 @@ fn actual_main() -> Result<()> {
                 .help("Don't print the output of the executed commands"),
         )
         .arg(
-            Arg::with_name("exit-on-error")
+            Arg::with_name("no-capture-on-success")
+                .long("no-capture-on-success")
+                .help("Print the output of the executed commands only if they fail"),
+        )
+        .arg(
+            Arg::with_name("exit")
                 .short("e")
                 .long("exit")
                 .help("Stop if any executed command returns with a nonzero exit code"),
         )
+        .arg(
+            Arg::with_name("no-exit")
+                .long("no-exit")
+                .conflicts_with("exit")
+                .help("Keep going after failures, overriding --ci"),
+        )
+        .arg(
+            Arg::with_name("locked")
+                .long("locked")
+                .help("Pass --locked to cargo commands"),
+        )
+        .arg(
+            Arg::with_name("no-locked")
+                .long("no-locked")
+                .conflicts_with("locked")
+                .help("Don't pass --locked to cargo commands, overriding --ci"),
+        )
+        .arg(
+            Arg::with_name("require-cargo-lock")
+                .long("require-cargo-lock")
+                .help("Skip crates without a Cargo.lock in their workspace"),
+        )
+        .arg(
+            Arg::with_name("skip-currently-building")
+                .long("skip-currently-building")
+                .help("Skip crates whose target directory is locked by a build in progress, instead of waiting for it"),
+        )
+        .arg(
+            Arg::with_name("ignore-if-no-cargo-lock")
+                .long("ignore-if-no-cargo-lock")
+                .help("Don't pass --locked to crates without a Cargo.lock in their workspace"),
+        )
+        .arg(
+            Arg::with_name("no-color")
+                .long("no-color")
+                .help("Ask the commands not to use colors"),
+        )
+        .arg(
+            Arg::with_name("ci")
+                .long("ci")
+                .help("Defaults for CI: --exit --locked --summary --no-progress --json-report ci-results.json, and --no-color if stdout is not a terminal. --locked isn't passed to cargo fmt, and each default can be overridden by its own flag"),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .short("j")
+                .long("jobs")
+                .default_value("1")
+                .help("Number of directories to run the command in at once"),
+        )
+        .arg(
+            Arg::with_name("ordered-output")
+                .long("ordered-output")
+                .conflicts_with("unordered-output")
+                .help("Print results in discovery order even when running in parallel (default)"),
+        )
+        .arg(
+            Arg::with_name("unordered-output")
+                .long("unordered-output")
+                .help("Print results as soon as each directory is done"),
+        )
         .arg(
             Arg::with_name("external")
                 .short("x")
                 .long("external")
                 .help("Run any command instead of a cargo command"),
         )
+        .arg(
+            Arg::with_name("limit-to-git-root")
+                .long("limit-to-git-root")
+                .help("Don't search outside the git repository containing the target directory"),
+        )
+        .arg(
+            Arg::with_name("skip-symlinked-dirs")
+                .long("skip-symlinked-dirs")
+                .conflicts_with("follow-symlinks")
+                .help("Don't search symlinked directories (default)"),
+        )
+        .arg(
+            Arg::with_name("follow-symlinks")
+                .long("follow-symlinks")
+                .help("Search symlinked directories too"),
+        )
+        .arg(
+            Arg::with_name("skip-examples-dir")
+                .long("skip-examples-dir")
+                .help("Don't search directories named `examples`"),
+        )
+        .arg(
+            Arg::with_name("skip-benches-dir")
+                .long("skip-benches-dir")
+                .help("Don't search directories named `benches`"),
+        )
+        .arg(
+            Arg::with_name("exclude-crates-io")
+                .long("exclude-crates-io")
+                .conflicts_with("only-crates-io")
+                .help("Skip crates that are published to crates.io, based on `package.publish`"),
+        )
+        .arg(
+            Arg::with_name("only-crates-io")
+                .long("only-crates-io")
+                .help("Only run in crates that are published to crates.io"),
+        )
+        .arg(
+            Arg::with_name("skip-unpublishable")
+                .long("skip-unpublishable")
+                .conflicts_with("only-unpublishable")
+                .help("Skip crates with `publish = false` or `publish = []`"),
+        )
+        .arg(
+            Arg::with_name("only-unpublishable")
+                .long("only-unpublishable")
+                .help("Only run in crates with `publish = false` or `publish = []`"),
+        )
+        .arg(
+            Arg::with_name("package-filter-script")
+                .long("package-filter-script")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Only run in crates for which this program, given the directory as its argument and Cargo.toml as stdin, exits successfully"),
+        )
+        .arg(
+            Arg::with_name("exclude-if-file-exists")
+                .long("exclude-if-file-exists")
+                .takes_value(true)
+                .value_name("FILENAME")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Skip crates containing a file with this name, e.g. `.skip-recursive`"),
+        )
+        .arg(
+            Arg::with_name("include-if-file-exists")
+                .long("include-if-file-exists")
+                .takes_value(true)
+                .value_name("FILENAME")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only run in crates containing a file with this name"),
+        )
+        .arg(
+            Arg::with_name("checksum-cache")
+                .long("checksum-cache")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Skip crates whose Cargo.toml and .rs files haven't changed since the command last succeeded, keeping hashes in DIR"),
+        )
+        .arg(
+            Arg::with_name("mode")
+                .long("mode")
+                .takes_value(true)
+                .possible_values(&["always", "timestamps", "checksums"])
+                .help("Whether to skip crates unchanged since the last successful run, by file modification times or hashes. The state is kept in the --checksum-cache directory, .cargo-recursive-cache by default"),
+        )
+        .arg(
+            Arg::with_name("skip-if-cargo-check-clean")
+                .long("skip-if-cargo-check-clean")
+                .help("Only run in crates where `cargo check` reports errors or warnings"),
+        )
+        .arg(
+            Arg::with_name("stdin-file")
+                .long("stdin-file")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("File to pipe to the command's stdin, {name} and {path} are replaced by the package name and directory"),
+        )
+        .arg(
+            Arg::with_name("target")
+                .long("target")
+                .takes_value(true)
+                .value_name("TRIPLE")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Run cargo commands once for each given target triple"),
+        )
+        .arg(
+            Arg::with_name("no-default-features")
+                .long("no-default-features")
+                .help("Pass --no-default-features to cargo commands"),
+        )
+        .arg(
+            Arg::with_name("feature-powerset")
+                .long("feature-powerset")
+                .help("Run cargo commands once for each combination of the crate's features, with --no-default-features"),
+        )
+        .arg(
+            Arg::with_name("max-feature-count")
+                .long("max-feature-count")
+                .takes_value(true)
+                .value_name("N")
+                .requires("feature-powerset")
+                .help("Only use feature combinations of at most N features"),
+        )
+        .arg(
+            Arg::with_name("skip-empty-features")
+                .long("skip-empty-features")
+                .requires("feature-powerset")
+                .help("Don't run without any features in --feature-powerset"),
+        )
+        .arg(
+            Arg::with_name("bench")
+                .long("bench")
+                .help("Select all benchmarks, passes --benches to cargo"),
+        )
+        .arg(
+            Arg::with_name("test")
+                .long("test")
+                .takes_value(true)
+                .value_name("NAME")
+                .help("Pass --test NAME to cargo"),
+        )
+        .arg(
+            Arg::with_name("example")
+                .long("example")
+                .takes_value(true)
+                .value_name("NAME")
+                .help("Pass --example NAME to cargo"),
+        )
+        .arg(
+            Arg::with_name("target-triple")
+                .long("target-triple")
+                .takes_value(true)
+                .value_name("TRIPLE")
+                .help("Like --target, but also set CARGO_BUILD_TARGET for build scripts that read it"),
+        )
+        .arg(
+            Arg::with_name("target-from-metadata")
+                .long("target-from-metadata")
+                .help("Only use targets listed in `package.metadata.recursive.targets`, if present"),
+        )
+        .arg(
+            Arg::with_name("respect-toolchain-file")
+                .long("respect-toolchain-file")
+                .help("Pass the toolchain from each crate's rust-toolchain(.toml) file to cargo as +toolchain"),
+        )
+        .arg(
+            Arg::with_name("diff-against")
+                .long("diff-against")
+                .takes_value(true)
+                .value_name("GIT_REF")
+                .help("After running, summarize `git diff <GIT_REF>` of each directory in the verbose output and JSON report"),
+        )
+        .arg(
+            Arg::with_name("extra-args-file")
+                .long("extra-args-file")
+                .takes_value(true)
+                .value_name("FILENAME")
+                .default_value(".recursive-extra-args")
+                .help("File in the directory with extra arguments for the command, one per line"),
+        )
+        .arg(
+            Arg::with_name("per-dir-timeout-file")
+                .long("per-dir-timeout-file")
+                .takes_value(true)
+                .value_name("FILENAME")
+                .help("Kill the command if it runs longer than the number of seconds in this file of the directory, if present"),
+        )
+        .arg(
+            Arg::with_name("exec-on-success")
+                .long("exec-on-success")
+                .takes_value(true)
+                .value_name("COMMAND")
+                .help("Shell command to run in the directory after the command succeeds"),
+        )
+        .arg(
+            Arg::with_name("exec-on-failure")
+                .long("exec-on-failure")
+                .takes_value(true)
+                .value_name("COMMAND")
+                .help("Shell command to run in the directory after the command fails"),
+        )
+        .arg(
+            Arg::with_name("on-error-run")
+                .long("on-error-run")
+                .takes_value(true)
+                .value_name("COMMAND")
+                .help("Shell command to run in the directory after the command fails, its output is printed and included in the JSON report"),
+        )
+        .arg(
+            Arg::with_name("git-stash-before")
+                .long("git-stash-before")
+                .help("Stash uncommitted changes to tracked files in the directory before running the command"),
+        )
+        .arg(
+            Arg::with_name("git-stash-pop-after")
+                .long("git-stash-pop-after")
+                .requires("git-stash-before")
+                .help("Restore the changes stashed by --git-stash-before after running the command"),
+        )
+        .arg(
+            Arg::with_name("log-groups")
+                .long("log-groups")
+                .possible_values(&["auto", "github", "gitlab", "none"])
+                .default_value("auto")
+                .help("Fold the output of each project into a collapsible CI log group"),
+        )
+        .arg(
+            Arg::with_name("output-file")
+                .long("output-file")
+                .takes_value(true)
+                .value_name("PATH")
+                .conflicts_with("append-output-file")
+                .help("Write the output of the commands and verbose messages to a file, overwriting it"),
+        )
+        .arg(
+            Arg::with_name("append-output-file")
+                .long("append-output-file")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Like --output-file, but appends to the file"),
+        )
+        .arg(
+            Arg::with_name("no-ansi")
+                .long("no-ansi")
+                .help("Strip ANSI escape sequences, e.g. colors, from the output of the commands"),
+        )
+        .arg(
+            Arg::with_name("group-errors")
+                .long("group-errors")
+                .help("Print the stderr of failed commands together after all directories are done"),
+        )
+        .arg(
+            Arg::with_name("deduplicate-errors")
+                .long("deduplicate-errors")
+                .help("Print the stderr of failed commands only once for identical error messages, with the number of repeats at the end"),
+        )
+        .arg(
+            Arg::with_name("progress")
+                .long("progress")
+                .conflicts_with("no-progress")
+                .help("Always show a progress indicator, by default it's shown only on terminals"),
+        )
+        .arg(
+            Arg::with_name("no-progress")
+                .long("no-progress")
+                .help("Never show a progress indicator"),
+        )
+        .arg(
+            Arg::with_name("measure-disk-usage")
+                .long("measure-disk-usage")
+                .help("Report the size of each crate's target directory, after running if not a dry run"),
+        )
+        .arg(
+            Arg::with_name("measure-memory")
+                .long("measure-memory")
+                .help("Report the peak virtual memory and RSS of the largest process started by each command, e.g. rustc under cargo, Linux only"),
+        )
+        .arg(
+            Arg::with_name("json-report")
+                .long("json-report")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Write the results as JSON to a file"),
+        )
+        .arg(
+            Arg::with_name("emit-ndjson")
+                .long("emit-ndjson")
+                .conflicts_with_all(&["aggregate-cargo-json", "dry-run", "artifact-sizes"])
+                .help("Write start, finish, skip, error and summary events to stdout as JSON lines while running, instead of the output of the commands"),
+        )
+        .arg(
+            Arg::with_name("list-failed")
+                .long("list-failed")
+                .takes_value(true)
+                .value_name("REPORT_FILE")
+                .help("Print the directories that failed in a --json-report and exit"),
+        )
+        .arg(
+            Arg::with_name("export-shell-aliases")
+                .long("export-shell-aliases")
+                .takes_value(true)
+                .value_name("SHELL")
+                .possible_values(&["bash", "zsh", "fish"])
+                .help("Print aliases crbuild, crtest and crcheck for the shell to eval, and exit"),
+        )
+        .arg(
+            Arg::with_name("show-durations")
+                .long("show-durations")
+                .help("Show how long the command took in each directory, and the fastest and slowest in the summary"),
+        )
+        .arg(
+            Arg::with_name("summary")
+                .long("summary")
+                .help("Print a summary of the results after running"),
+        )
+        .arg(
+            Arg::with_name("no-summary")
+                .long("no-summary")
+                .conflicts_with("summary")
+                .help("Don't print a summary, overriding --ci"),
+        )
+        .arg(
+            Arg::with_name("aggregate-cargo-json")
+                .long("aggregate-cargo-json")
+                .help("Print the JSON messages of a cargo command given --message-format=json as one JSON lines stream, adding manifest_path where missing"),
+        )
+        .arg(
+            Arg::with_name("artifact-sizes")
+                .long("artifact-sizes")
+                .help("Print the sizes of the binaries and cdylibs produced by a cargo build command"),
+        )
+        .arg(
+            Arg::with_name("artifact-sort")
+                .long("artifact-sort")
+                .possible_values(&["size", "name"])
+                .default_value("size")
+                .help("Sort order of the --artifact-sizes table"),
+        )
+        .arg(
+            Arg::with_name("working-dir")
+                .long("working-dir")
+                .takes_value(true)
+                .value_name("RELATIVE_PATH")
+                .help("Run the command in this subdirectory of each crate"),
+        )
+        .arg(
+            Arg::with_name("pass-directory-as-env")
+                .long("pass-directory-as-env")
+                .takes_value(true)
+                .value_name("VAR")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Set an environment variable to the absolute path of the directory"),
+        )
+        .arg(
+            Arg::with_name("unset-env")
+                .long("unset-env")
+                .takes_value(true)
+                .value_name("KEY")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Remove an environment variable, e.g. RUSTFLAGS, from the environment of the commands"),
+        )
+        .arg(
+            Arg::with_name("cargo-env-passthrough")
+                .long("cargo-env-passthrough")
+                .help("Explicitly set every CARGO_* variable of this process on the commands, even if removed with --unset-env"),
+        )
+        .arg(
+            Arg::with_name("fail-on-warning")
+                .long("fail-on-warning")
+                .help("Add `-D warnings` to RUSTFLAGS of the commands, so that compiler warnings are errors"),
+        )
+        .arg(
+            Arg::with_name("print-env")
+                .long("print-env")
+                .help("Print the environment of each command to stderr before running it, marking added (+) and removed (-) variables"),
+        )
+        .arg(
+            Arg::with_name("pass-cargo-env")
+                .long("pass-cargo-env")
+                .help("Set the CARGO_PKG_* and CARGO_MANIFEST_* variables cargo uses for builds, based on the manifest"),
+        )
+        .arg(
+            Arg::with_name("inject-workspace-root")
+                .long("inject-workspace-root")
+                .takes_value(true)
+                .value_name("VAR")
+                .help("Set an environment variable to the absolute path of the crate's workspace root"),
+        )
+        .arg(
+            Arg::with_name("assert-package-names-unique")
+                .long("assert-package-names-unique")
+                .help("Fail if multiple crates have the same package name, the command is optional"),
+        )
+        .arg(
+            Arg::with_name("package-graph-check")
+                .long("package-graph-check")
+                .help("Fail before running anything if the path dependencies between the crates form a cycle, the command is optional"),
+        )
+        .arg(
+            Arg::with_name("set-manifest-field")
+                .long("set-manifest-field")
+                .takes_value(true)
+                .value_name("KEY=VALUE")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Set a dotted field in each Cargo.toml before running, the command is optional. The value is a string unless it is true, false, quoted, an array or an inline table. With --dry-run print a diff instead"),
+        )
+        .arg(
+            Arg::with_name("generate-makefile")
+                .long("generate-makefile")
+                .takes_value(true)
+                .value_name("OUTPUT")
+                .help("Write a Makefile with a target running the command for each crate, ordered by dependencies, instead of running it"),
+        )
+        .arg(
+            Arg::with_name("explain")
+                .long("explain")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Show why a directory would or wouldn't be processed, without running anything"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Output format of reports"),
+        )
+        .arg(
+            Arg::with_name("verify-all-compile")
+                .long("verify-all-compile")
+                .conflicts_with_all(&["command", "external"])
+                .help("Run `cargo check` in each crate and only report whether it compiles, --verbose shows the compiler messages"),
+        )
+        .arg(
+            Arg::with_name("error-prefix")
+                .long("error-prefix")
+                .takes_value(true)
+                .value_name("TEXT")
+                .help("Prefix of error messages, by default \"Error: \""),
+        )
+        .arg(
+            Arg::with_name("error-chain-indent")
+                .long("error-chain-indent")
+                .takes_value(true)
+                .value_name("TEXT")
+                .help("Indent of the causes listed below error messages, by default four spaces"),
+        )
         .arg(
             Arg::with_name("command")
                 .multiple(true)
                 .help("The command to run"),
         )
-        .get_matches_from(&args);
+        .arg(
+            Arg::with_name("home")
+                .long("home")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Global configuration directory with config.toml and ignore files, by default ~/.config/cargo-recursive"),
+        );
+
+    // Defaults from the global config.toml are inserted before the given arguments
+    let matches = app.clone().get_matches_from(&args);
+    let home = match matches.value_of("home") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => config::home_dir(),
+    };
+    let matches = match &home {
+        Some(home) => {
+            let defaults = config::default_args(home, |name, defaults| {
+                matches.occurrences_of(name) != 0 || conflicts_with_given(&app, &args, defaults)
+            })?;
+            if defaults.is_empty() {
+                matches
+            } else {
+                let mut all = vec![args[0].clone()];
+                all.extend(defaults);
+                all.extend(args[1..].iter().cloned());
+                // The given arguments were valid, so errors are from the config
+                app.get_matches_from_safe(&all)
+                    .map_err(|e| anyhow!("{}", e.message))
+                    .with_context(|| {
+                        format!("applying defaults from {:?}", home.join("config.toml"))
+                    })?
+            }
+        }
+        None => matches,
+    };
+
+    let _ = ERROR_FORMAT.set((
+        matches
+            .value_of("error-prefix")
+            .unwrap_or("Error: ")
+            .to_owned(),
+        matches
+            .value_of("error-chain-indent")
+            .unwrap_or("    ")
+            .to_owned(),
+    ));
+
+    if let Some(shell) = matches.value_of("export-shell-aliases") {
+        print!("{}", shell_aliases(shell));
+        return Ok(());
+    }
+
+    if let Some(file) = matches.value_of("list-failed") {
+        for path in report::read_failed(Path::new(file))? {
+            println!("{}", path);
+        }
+        return Ok(());
+    }
 
     let depth: usize = matches
         .value_of("depth")
@@ -87,107 +749,1483 @@ fn actual_main() -> Result<()> {
 
     let dry_run: bool = matches.is_present("dry-run");
     let verbose: bool = matches.is_present("verbose");
-    let output: bool = !matches.is_present("suppress-output");
-    let exit_on_error: bool = matches.is_present("exit-on-error");
+    let emit_ndjson = matches.is_present("emit-ndjson");
+    // The output of the commands would break the event stream
+    let output: bool = !matches.is_present("suppress-output") && !emit_ndjson;
+    let ci: bool = matches.is_present("ci");
+    let exit_on_error: bool = matches.is_present("exit") || (ci && !matches.is_present("no-exit"));
     let external: bool = matches.is_present("external");
-    let args = matches
-        .values_of("command")
-        .map(|vals| vals.collect::<Vec<_>>())
-        .expect("Argument command invalid or missing");
+    let json_format = matches.value_of("format") == Some("json");
 
-    let cmd = CommandInfo {
-        external,
-        args,
-        output,
-        exit_on_error,
+    let git_root = if matches.is_present("limit-to-git-root") {
+        Some(scan::find_git_root(&path)?)
+    } else {
+        None
     };
 
-    process_dir(Path::new(&path), depth, verbose, dry_run, &cmd)?;
+    let offset = match matches.value_of("skip-after") {
+        Some(n) => n.parse().with_context(|| "skip-after must be an integer")?,
+        None => 0,
+    };
+    let limit = match matches.value_of("stop-after") {
+        Some(n) => Some(n.parse().with_context(|| "stop-after must be an integer")?),
+        None => None,
+    };
 
-    Ok(())
-}
+    let depth_offset = if matches.is_present("depth-from-workspace-root") {
+        let workspace_root = scan::find_workspace_root(&path)?;
+        scan::absolute(&path)?
+            .strip_prefix(&workspace_root)
+            .map(|p| p.components().count())
+            .unwrap_or(0)
+    } else {
+        0
+    };
 
-fn process_dir(
-    path: &Path,
-    depth: usize,
-    verbose: bool,
-    dry_run: bool,
-    cmd: &CommandInfo,
-) -> Result<()> {
-    if depth == 0 {
+    let scanner = Scanner {
+        root: path,
+        max_depth: depth,
+        depth_offset,
+        skip_examples: matches.is_present("skip-examples-dir"),
+        skip_benches: matches.is_present("skip-benches-dir"),
+        ignore: match &home {
+            Some(home) => config::ignore_patterns(home)?,
+            None => Vec::new(),
+        },
+        include: config::include_patterns(),
+        exclude_if_file_exists: matches
+            .values_of("exclude-if-file-exists")
+            .map(|vals| vals.map(str::to_owned).collect())
+            .unwrap_or_default(),
+        include_if_file_exists: matches
+            .values_of("include-if-file-exists")
+            .map(|vals| vals.map(str::to_owned).collect())
+            .unwrap_or_default(),
+        require_cargo_lock: matches.is_present("require-cargo-lock"),
+        skip_building: matches.is_present("skip-currently-building"),
+        filter_script: matches.value_of("package-filter-script").map(str::to_owned),
+        skip_if_check_clean: matches.is_present("skip-if-cargo-check-clean"),
+        crates_io: if matches.is_present("only-crates-io") {
+            Some(true)
+        } else if matches.is_present("exclude-crates-io") {
+            Some(false)
+        } else {
+            None
+        },
+        publishable: if matches.is_present("skip-unpublishable") {
+            Some(true)
+        } else if matches.is_present("only-unpublishable") {
+            Some(false)
+        } else {
+            None
+        },
+        git_root,
+        follow_symlinks: matches.is_present("follow-symlinks"),
+        resolver: matches.value_of("external-resolver").map(str::to_owned),
+        sort_by: match matches.value_of("sort-by") {
+            Some("name") => scan::SortBy::Name,
+            Some("path") => scan::SortBy::Path,
+            Some("depth") => scan::SortBy::Depth,
+            Some("mod-time") => scan::SortBy::ModTime,
+            _ => scan::SortBy::Discovery,
+        },
+        reverse: matches.is_present("reverse"),
+        offset,
+        limit,
+        verbose,
+    };
+
+    if let Some(target) = matches.value_of("explain") {
+        let explanation = scanner.explain(Path::new(target))?;
+        if json_format {
+            println!("{}", explanation.to_json());
+        } else {
+            explanation.print_text();
+        }
         return Ok(());
     }
 
-    if path.join("Cargo.toml").exists() {
-        if verbose {
-            eprintln!("Running in {:?}", path);
+    let field_updates = matches
+        .values_of("set-manifest-field")
+        .map(|vals| vals.map(FieldUpdate::parse).collect::<Result<Vec<_>>>())
+        .transpose()?
+        .unwrap_or_default();
+
+    let verify_compile = matches.is_present("verify-all-compile");
+    let assert_unique_names = matches.is_present("assert-package-names-unique");
+    let package_graph_check = matches.is_present("package-graph-check");
+    let args = match matches.values_of("command") {
+        Some(vals) => vals.collect::<Vec<_>>(),
+        None if verify_compile => vec!["check", "--message-format=json"],
+        None if assert_unique_names || package_graph_check || !field_updates.is_empty() => {
+            let dirs = scanner
+                .scan(exit_on_error)?
+                .into_dirs(|message| eprintln!("{}", message));
+            if package_graph_check {
+                check_package_graph(&dirs)?;
+            }
+            update_manifests(&dirs, &field_updates, dry_run, verbose, exit_on_error)?;
+            if assert_unique_names {
+                check_unique_names(&dirs)?;
+            }
+            return Ok(());
         }
+        None => bail!("No command given"),
+    };
 
-        if !dry_run {
-            cmd.run(path)
-                .with_context(|| format!("running in directory {:?}", path))?;
+    // `cargo fmt` doesn't resolve dependencies and rejects --locked
+    let subcommand = args.iter().find(|a| !a.starts_with('+'));
+    let ci_locked =
+        ci && !matches.is_present("no-locked") && (external || subcommand != Some(&"fmt"));
+    let locked = matches.is_present("locked") || ci_locked;
+    if let Some(output) = matches.value_of("generate-makefile") {
+        let mut command: Vec<String> = args.iter().map(|&a| a.to_owned()).collect();
+        if !external {
+            if locked {
+                insert_cargo_args(&mut command, &["--locked"]);
+            }
+            command.insert(0, "cargo".to_owned());
+        }
+        let dirs = scanner
+            .scan(exit_on_error)?
+            .into_dirs(|message| eprintln!("{}", message));
+        if package_graph_check {
+            check_package_graph(&dirs)?;
         }
+        return makefile::generate(&dirs, &command, Path::new(output), verbose);
     }
 
-    for e in path
-        .read_dir()
-        .with_context(|| format!("reading directory {:?}", path.canonicalize()))?
-    {
-        let e = e?;
-        if e.file_type()?.is_dir() {
-            if let Err(e) = process_dir(&e.path(), depth - 1, verbose, dry_run, cmd) {
-                if cmd.exit_on_error {
-                    return Err(e);
-                }
-                eprintln!("Warn: {}", e);
-                for c in e.chain().skip(1) {
-                    eprintln!("    {}", c);
-                }
-            }
+    let artifact_sizes = matches.is_present("artifact-sizes");
+    if artifact_sizes {
+        artifacts::check_command(external, &args)?;
+    }
+
+    let mut targets: Vec<&str> = matches
+        .values_of("target")
+        .map(|vals| vals.collect())
+        .unwrap_or_default();
+    let target_env = matches.value_of("target-triple");
+    targets.extend(target_env);
+    let target_from_metadata = matches.is_present("target-from-metadata");
+    if external && (!targets.is_empty() || target_from_metadata) {
+        bail!("--target can only be used with cargo commands");
+    }
+    let respect_toolchain_file = matches.is_present("respect-toolchain-file");
+    if external && respect_toolchain_file {
+        bail!("--respect-toolchain-file can only be used with cargo commands");
+    }
+    let aggregate_json = matches.is_present("aggregate-cargo-json");
+    if aggregate_json {
+        if external {
+            bail!("--aggregate-cargo-json can only be used with cargo commands");
+        }
+        if !args.iter().any(|a| a.starts_with("--message-format")) {
+            bail!("--aggregate-cargo-json requires the command to use --message-format=json");
         }
     }
 
-    Ok(())
-}
+    let feature_powerset = matches.is_present("feature-powerset");
+    if external && feature_powerset {
+        bail!("--feature-powerset can only be used with cargo commands");
+    }
+    let max_feature_count = match matches.value_of("max-feature-count") {
+        Some(n) => Some(
+            n.parse()
+                .with_context(|| "max-feature-count must be an integer")?,
+        ),
+        None => None,
+    };
 
-#[derive(Debug)]
-struct CommandInfo<'a> {
-    /// Use external binary (i.e. from PATH or absolute path)
-    /// instead of implicitly using `cargo` as the binary
-    external: bool,
-    /// Arguments, see above for the first item
+    let mut target_selection = Vec::new();
+    if matches.is_present("bench") {
+        target_selection.push("--benches");
+    }
+    if let Some(name) = matches.value_of("test") {
+        target_selection.extend(["--test", name]);
+    }
+    if let Some(name) = matches.value_of("example") {
+        target_selection.extend(["--example", name]);
+    }
+    if external && !target_selection.is_empty() {
+        bail!("--bench, --test and --example can only be used with cargo commands");
+    }
+
+    let cmd = CommandInfo {
+        external,
+        args,
+        output,
+        stdin_file: matches.value_of("stdin-file"),
+        artifact_sizes,
+        targets,
+        target_env,
+        target_from_metadata,
+        respect_toolchain_file,
+        directory_env: matches
+            .values_of("pass-directory-as-env")
+            .map(|vals| vals.collect())
+            .unwrap_or_default(),
+        workspace_root_env: matches.value_of("inject-workspace-root"),
+        cargo_env: matches.is_present("pass-cargo-env"),
+        print_env: matches.is_present("print-env"),
+        measure_memory: matches.is_present("measure-memory"),
+        git_stash: matches.is_present("git-stash-before"),
+        git_stash_pop: matches.is_present("git-stash-pop-after"),
+        fail_on_warning: matches.is_present("fail-on-warning"),
+        cargo_env_passthrough: matches.is_present("cargo-env-passthrough"),
+        unset_env: matches
+            .values_of("unset-env")
+            .map(|vals| vals.collect())
+            .unwrap_or_default(),
+        working_dir: matches.value_of("working-dir"),
+        timeout_file: matches.value_of("per-dir-timeout-file"),
+        extra_args_file: matches
+            .value_of("extra-args-file")
+            .expect("'extra-args-file' missing"),
+        locked,
+        locked_needs_lock_file: matches.is_present("ignore-if-no-cargo-lock"),
+        target_selection,
+        no_default_features: matches.is_present("no-default-features"),
+        feature_powerset,
+        max_feature_count,
+        skip_empty_features: matches.is_present("skip-empty-features"),
+        no_color: matches.is_present("no-color") || (ci && !io::stdout().is_terminal()),
+        verify_compile,
+        aggregate_json,
+    };
+
+    let log_groups = if json_format {
+        LogGroups::None
+    } else {
+        LogGroups::from_arg(
+            matches
+                .value_of("log-groups")
+                .expect("'log-groups' missing"),
+        )
+    };
+    let output_file = if let Some(path) = matches.value_of("output-file") {
+        Some(File::create(path).with_context(|| format!("creating output file {:?}", path))?)
+    } else if let Some(path) = matches.value_of("append-output-file") {
+        Some(
+            OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .with_context(|| format!("opening output file {:?}", path))?,
+        )
+    } else {
+        None
+    };
+    let mut printer = Printer::new(log_groups, output_file);
+    printer.strip_ansi = matches.is_present("no-ansi");
+    printer.group_errors = matches.is_present("group-errors");
+    printer.deduplicate_errors = matches.is_present("deduplicate-errors");
+    printer.progress = if matches.is_present("progress") {
+        true
+    } else if matches.is_present("no-progress") || ci {
+        false
+    } else {
+        io::stderr().is_terminal()
+    };
+
+    let diff_against = matches.value_of("diff-against");
+    let on_success = matches.value_of("exec-on-success");
+    let on_failure = matches.value_of("exec-on-failure");
+    let on_error_run = matches.value_of("on-error-run");
+
+    let jobs_count: usize = matches
+        .value_of("jobs")
+        .expect("'jobs' missing")
+        .parse()
+        .with_context(|| "jobs must be an integer")?;
+    if jobs_count == 0 {
+        bail!("jobs must be at least 1");
+    }
+    if cmd.git_stash && jobs_count > 1 {
+        // Parallel jobs would pop each other's stashes
+        bail!("--git-stash-before can't be used with --jobs");
+    }
+    let ordered_output = !matches.is_present("unordered-output");
+    let show_durations = matches.is_present("show-durations");
+    let only_failed_output = matches.is_present("no-capture-on-success");
+    if cfg!(not(target_os = "linux")) && cmd.measure_memory {
+        bail!("--measure-memory is only supported on Linux");
+    }
+
+    let mut artifacts = Vec::new();
+    let mut report = Report::new(&scanner.root);
+    let mut failure = None;
+
+    let dirs = scanner
+        .scan(exit_on_error)?
+        .into_dirs(|message| printer.note(message));
+    if package_graph_check {
+        check_package_graph(&dirs)?;
+    }
+    update_manifests(&dirs, &field_updates, dry_run, verbose, exit_on_error)?;
+    let mode = match matches.value_of("mode") {
+        Some("timestamps") => RecursiveMode::TrustTimestamps,
+        Some("checksums") => RecursiveMode::ChecksumBased,
+        Some(_) => RecursiveMode::AlwaysRun,
+        None if matches.is_present("checksum-cache") => RecursiveMode::ChecksumBased,
+        None => RecursiveMode::AlwaysRun,
+    };
+    let state_cache = if mode == RecursiveMode::AlwaysRun {
+        None
+    } else {
+        Some(StateCache {
+            dir: match matches.value_of("checksum-cache") {
+                Some(dir) => PathBuf::from(dir),
+                None => scanner.root.join(".cargo-recursive-cache"),
+            },
+            mode,
+        })
+    };
+    let started = SystemTime::now();
+    // States to store for crates that succeed
+    let mut new_states = Vec::new();
+    let mut jobs = Vec::new();
+    for dir in &dirs {
+        if let Some(cache) = &state_cache {
+            match cache.check(dir, started) {
+                Ok(None) => {
+                    if verbose {
+                        printer.note(&format!("Skipping {:?}: unchanged since last run", dir));
+                    }
+                    continue;
+                }
+                Ok(Some(state)) => new_states.push((dir, state)),
+                Err(e) => warn(&e.context(format!("checking for changes in {:?}", dir))),
+            }
+        }
+        match cmd
+            .targets(dir)
+            .and_then(|t| Ok((t, cmd.feature_sets(dir)?)))
+        {
+            Ok((targets, feature_sets)) => {
+                for target in targets {
+                    jobs.extend(feature_sets.iter().map(|features| Job {
+                        dir: dir.clone(),
+                        target: target.clone(),
+                        features: features.clone(),
+                    }));
+                }
+            }
+            Err(e) => {
+                let e = e.context(format!("running in directory {:?}", dir));
+                if exit_on_error {
+                    return Err(e);
+                }
+                warn(&e);
+            }
+        }
+    }
+
+    if dry_run {
+        for job in &jobs {
+            match job.label() {
+                Some(label) => println!("{} ({})", job.dir.display(), label),
+                None => println!("{}", job.dir.display()),
+            }
+        }
+    }
+
+    // Handles the result of a single job, returns false to stop the run
+    let mut handle = |printer: &mut Printer, job: &Job, result: Result<Outcome>| -> bool {
+        printer.clear_progress();
+        let dir = &job.dir;
+        let target = job.target.as_deref();
+        let features = job.features.as_deref();
+        let mut duration = None;
+        let memory;
+        let took;
+        let status = match result.with_context(|| format!("running in {}", job.location())) {
+            Ok(outcome) => {
+                let mut title = report.display_path(dir);
+                if let Some(label) = job.label() {
+                    title = format!("{} ({})", title, label);
+                }
+                if cmd.aggregate_json {
+                    // Titles or log groups would break the JSON lines
+                    printer.print_streams(&outcome.stdout, &outcome.stderr);
+                } else if cmd.output
+                    && (verbose || !cmd.verify_compile)
+                    && !(only_failed_output && outcome.status == Status::Success)
+                {
+                    printer.print_output(&title, outcome.status, &outcome.stdout, &outcome.stderr);
+                }
+                if let Some(diagnostics) = outcome.diagnostics.as_ref().filter(|_| !emit_ndjson) {
+                    let verdict = match outcome.status {
+                        Status::Success => "ok",
+                        _ => "FAILED",
+                    };
+                    let line = format!("{:<6} {} ({})\n", verdict, title, diagnostics.describe());
+                    printer.print_streams(line.as_bytes(), &[]);
+                }
+                if show_durations {
+                    let took = report::format_duration(outcome.duration);
+                    printer.note(&format!("{} took {}", title, took));
+                    duration = Some(outcome.duration);
+                }
+                took = outcome.duration;
+                memory = outcome.memory;
+                artifacts.extend(outcome.artifacts);
+                outcome.status
+            }
+            Err(e) if is_skip(&e, exit_on_error) => {
+                if emit_ndjson {
+                    emit_event(
+                        "skip",
+                        Some(job),
+                        vec![("message", format!("{:#}", e).into())],
+                    );
+                }
+                warn(&e);
+                return true;
+            }
+            Err(e) => {
+                report.push(dir, target, features, Status::Error);
+                if emit_ndjson {
+                    emit_event(
+                        "error",
+                        Some(job),
+                        vec![("message", format!("{:#}", e).into())],
+                    );
+                }
+                if exit_on_error {
+                    failure = Some(e);
+                    return false;
+                }
+                warn(&e);
+                return true;
+            }
+        };
+        report.push(dir, target, features, status);
+        if let Some(duration) = duration {
+            report.set_duration(duration);
+        }
+        if let Some(memory) = memory {
+            report.set_memory(memory);
+        }
+        if emit_ndjson {
+            emit_event(
+                "finish",
+                Some(job),
+                vec![
+                    ("success", (status == Status::Success).into()),
+                    ("status", status.describe().into()),
+                    ("exit_code", status.exit_code_json()),
+                    ("duration_secs", json::Value::Number(took.as_secs_f64())),
+                ],
+            );
+        }
+
+        if let Some(reference) = diff_against {
+            match git_diff_summary(dir, reference) {
+                Ok(summary) => {
+                    if verbose {
+                        printer.note(&format!(
+                            "Changes in {} since {}: {}",
+                            report.display_path(dir),
+                            reference,
+                            summary
+                        ));
+                    }
+                    report.set_diff(summary);
+                }
+                Err(e) => warn(&e.context(format!("diffing {}", job.location()))),
+            }
+        }
+
+        if let (Some(command), Status::Failed(_)) = (on_error_run, status) {
+            match run_hook(command, dir) {
+                Ok(output) => {
+                    if cmd.output {
+                        printer.print_streams(&output.stdout, &output.stderr);
+                    }
+                    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+                    text += &String::from_utf8_lossy(&output.stderr);
+                    report.set_error_output(text);
+                }
+                Err(e) => warn(&e.context(format!("running diagnostics in {}", job.location()))),
+            }
+        }
+
+        let hook = match status {
+            Status::Success => on_success,
+            Status::Failed(_) => on_failure,
+            Status::Error => None,
+        };
+        if let Some(hook) = hook {
+            match run_hook(hook, dir) {
+                Ok(output) if cmd.output => printer.print_streams(&output.stdout, &output.stderr),
+                Ok(_) => {}
+                Err(e) => warn(&e.context(format!("running hook in {}", job.location()))),
+            }
+        }
+
+        if exit_on_error {
+            if let Status::Failed(code) = status {
+                let e = match code {
+                    Some(code) => anyhow!("Command returned a nonzero code {}", code),
+                    None => anyhow!("Command returned an error"),
+                };
+                failure = Some(e.context(format!("running in {}", job.location())));
+                return false;
+            }
+        }
+        true
+    };
+
+    let run_started = Instant::now();
+    if !dry_run && !jobs.is_empty() {
+        let next = AtomicUsize::new(0);
+        let stop = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let (tx, rx) = mpsc::channel();
+            for _ in 0..jobs_count.min(jobs.len()) {
+                let tx = tx.clone();
+                let (next, stop, jobs, cmd) = (&next, &stop, &jobs, &cmd);
+                scope.spawn(move || loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    if index >= jobs.len() || stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let job = &jobs[index];
+                    let _ = tx.send(JobEvent::Started(index));
+                    let result = cmd.run(job);
+                    let _ = tx.send(JobEvent::Finished(index, result));
+                });
+            }
+            drop(tx);
+
+            // Finished jobs waiting for earlier ones, when ordering output
+            let mut pending = BTreeMap::new();
+            let mut next_handled = 0;
+            let mut finished = 0;
+            let mut current = String::new();
+            for event in rx {
+                if stop.load(Ordering::SeqCst) {
+                    continue;
+                }
+                if let JobEvent::Finished(..) = event {
+                    finished += 1;
+                }
+                let keep_going = match event {
+                    JobEvent::Started(index) => {
+                        if emit_ndjson {
+                            emit_event("start", Some(&jobs[index]), Vec::new());
+                        }
+                        if verbose {
+                            printer.note(&format!("Running in {}", jobs[index].location()));
+                        }
+                        current = report::relative_path(&scanner.root, &jobs[index].dir);
+                        true
+                    }
+                    JobEvent::Finished(index, result) if ordered_output => {
+                        pending.insert(index, result);
+                        let mut keep_going = true;
+                        while keep_going {
+                            match pending.remove(&next_handled) {
+                                Some(result) => {
+                                    keep_going = handle(&mut printer, &jobs[next_handled], result);
+                                    next_handled += 1;
+                                }
+                                None => break,
+                            }
+                        }
+                        keep_going
+                    }
+                    JobEvent::Finished(index, result) => handle(&mut printer, &jobs[index], result),
+                };
+                if !keep_going {
+                    stop.store(true, Ordering::SeqCst);
+                }
+                printer.show_progress(&format!("[{}/{}] {}", finished, jobs.len(), current));
+            }
+            printer.clear_progress();
+        });
+    }
+
+    printer.print_errors();
+    if let Some(cache) = &state_cache {
+        for (dir, state) in &new_states {
+            let mut runs = report.entries.iter().filter(|e| e.dir == **dir).peekable();
+            if runs.peek().is_some() && runs.all(|e| e.status == Status::Success) {
+                if let Err(e) = cache.store(dir, state) {
+                    warn(&e);
+                }
+            }
+        }
+    }
+    if emit_ndjson {
+        let failed = report
+            .entries
+            .iter()
+            .filter(|e| e.status != Status::Success)
+            .count();
+        emit_event(
+            "summary",
+            None,
+            vec![
+                ("runs", report.entries.len().into()),
+                ("succeeded", (report.entries.len() - failed).into()),
+                ("failed", failed.into()),
+                (
+                    "elapsed_secs",
+                    json::Value::Number(run_started.elapsed().as_secs_f64()),
+                ),
+            ],
+        );
+    }
+    let measure_disk_usage = matches.is_present("measure-disk-usage");
+    if measure_disk_usage {
+        report.measure_disk_usage(&dirs);
+    }
+    if show_durations {
+        report.elapsed = Some(run_started.elapsed());
+    }
+    let measure_memory = cmd.measure_memory && !dry_run;
+    let ci_summary = ci && !matches.is_present("no-summary");
+    if matches.is_present("summary")
+        || measure_disk_usage
+        || show_durations
+        || measure_memory
+        || ci_summary
+    {
+        report.print_summary();
+    }
+    let json_report = match matches.value_of("json-report") {
+        Some(path) => Some(path),
+        // Nothing ran on a dry run, so there is nothing to report
+        None if ci && !dry_run => Some("ci-results.json"),
+        None => None,
+    };
+    if let Some(path) = json_report {
+        report.write_json(Path::new(path))?;
+    }
+
+    if let Some(e) = failure {
+        return Err(e);
+    }
+
+    if verify_compile {
+        let failed = report
+            .entries
+            .iter()
+            .filter(|e| e.status != Status::Success)
+            .count();
+        if failed != 0 {
+            bail!("{} crates failed to compile", failed);
+        }
+    }
+
+    if artifact_sizes && !dry_run {
+        let sort_by = match matches.value_of("artifact-sort") {
+            Some("name") => artifacts::SortBy::Name,
+            _ => artifacts::SortBy::Size,
+        };
+        artifacts::print_table(&artifacts, sort_by, json_format);
+    }
+
+    if assert_unique_names {
+        check_unique_names(&dirs)?;
+    }
+
+    Ok(())
+}
+
+/// A single run of the command
+#[derive(Debug)]
+struct Job {
+    dir: PathBuf,
+    target: Option<String>,
+    /// Comma separated features, when running for each combination of features
+    features: Option<String>,
+}
+impl Job {
+    /// Description for messages
+    fn location(&self) -> String {
+        let mut location = format!("directory {:?}", self.dir);
+        if let Some(t) = &self.target {
+            location += &format!(" for target {}", t);
+        }
+        if let Some(f) = &self.features {
+            location += &format!(" with features [{}]", f);
+        }
+        location
+    }
+
+    /// Target and features to show after the directory, if any
+    fn label(&self) -> Option<String> {
+        let features = self.features.as_ref().map(|f| format!("[{}]", f));
+        match (&self.target, features) {
+            (Some(t), Some(f)) => Some(format!("{}, {}", t, f)),
+            (Some(t), None) => Some(t.clone()),
+            (None, f) => f,
+        }
+    }
+}
+
+/// Progress of a job, sent from the worker threads
+enum JobEvent {
+    Started(usize),
+    Finished(usize, Result<Outcome>),
+}
+
+#[derive(Debug)]
+struct CommandInfo<'a> {
+    /// Use external binary (i.e. from PATH or absolute path)
+    /// instead of implicitly using `cargo` as the binary
+    external: bool,
+    /// Arguments, see above for the first item
     args: Vec<&'a str>,
     /// Display output of the command after execution
     output: bool,
-    /// Exit on error
-    exit_on_error: bool,
+    /// Template for the path of the file piped to stdin
+    stdin_file: Option<&'a str>,
+    /// Collect the artifacts produced, see `artifacts` module
+    artifact_sizes: bool,
+    /// Target triples to run for, each as a separate run
+    targets: Vec<&'a str>,
+    /// Target triple to also set as `CARGO_BUILD_TARGET` when running for it
+    target_env: Option<&'a str>,
+    /// Limit targets to those listed in the crate's `package.metadata.recursive.targets`
+    target_from_metadata: bool,
+    /// Select the toolchain based on the crate's toolchain file
+    respect_toolchain_file: bool,
+    /// Environment variables set to the absolute path of the directory
+    directory_env: Vec<&'a str>,
+    /// Environment variable set to the absolute path of the workspace root
+    workspace_root_env: Option<&'a str>,
+    /// Set the package environment variables of cargo from the manifest
+    cargo_env: bool,
+    /// Print the environment of the command before running it
+    print_env: bool,
+    /// Sample the memory usage of the command while it runs
+    measure_memory: bool,
+    /// Stash uncommitted changes in the directory before running the command
+    git_stash: bool,
+    /// Pop the stashed changes after running the command
+    git_stash_pop: bool,
+    /// Add `-D warnings` to `RUSTFLAGS`
+    fail_on_warning: bool,
+    /// Set the `CARGO_*` variables of this process explicitly, overriding `unset_env`
+    cargo_env_passthrough: bool,
+    /// Environment variables removed from the environment of the command
+    unset_env: Vec<&'a str>,
+    /// Run in this subdirectory instead of the crate root
+    working_dir: Option<&'a str>,
+    /// File in the directory with the timeout of the command in seconds
+    timeout_file: Option<&'a str>,
+    /// File in the directory with extra arguments, one per line
+    extra_args_file: &'a str,
+    /// Pass `--locked` to cargo
+    locked: bool,
+    /// Only pass `--locked` if the workspace has a `Cargo.lock`
+    locked_needs_lock_file: bool,
+    /// Cargo arguments selecting the targets to build, e.g. `--test foo`
+    target_selection: Vec<&'a str>,
+    /// Pass `--no-default-features` to cargo commands
+    no_default_features: bool,
+    /// Run once for each combination of the crate's features
+    feature_powerset: bool,
+    /// Largest number of features in a combination
+    max_feature_count: Option<usize>,
+    /// Leave out the combination without features
+    skip_empty_features: bool,
+    /// Ask the command not to use colors
+    no_color: bool,
+    /// Running `cargo check` for `--verify-all-compile`, collect the diagnostics
+    verify_compile: bool,
+    /// Tag cargo's JSON messages with the manifest path, for merging them
+    aggregate_json: bool,
 }
 impl<'a> CommandInfo<'a> {
-    fn run(&self, path: &Path) -> Result<()> {
-        let mut args = self.args.clone();
+    /// Targets to run for in `path`, a single `None` if not running for specific targets
+    fn targets(&self, path: &Path) -> Result<Vec<Option<String>>> {
+        let mut targets: Vec<String> = self.targets.iter().map(|&t| t.to_owned()).collect();
+        if self.target_from_metadata {
+            let manifest = Manifest::read(path)?;
+            if let Some(allowed) = manifest
+                .get("package.metadata.recursive.targets")
+                .and_then(toml_edit::Item::as_array)
+            {
+                let allowed: Vec<&str> = allowed
+                    .iter()
+                    .filter_map(toml_edit::Value::as_str)
+                    .collect();
+                if targets.is_empty() {
+                    return Ok(allowed.into_iter().map(|t| Some(t.to_owned())).collect());
+                }
+                targets.retain(|t| allowed.contains(&t.as_str()));
+                return Ok(targets.into_iter().map(Some).collect());
+            }
+        }
+
+        if targets.is_empty() {
+            Ok(vec![None])
+        } else {
+            Ok(targets.into_iter().map(Some).collect())
+        }
+    }
+
+    /// Feature combinations to run for in `path`, a single `None` if not running for each
+    fn feature_sets(&self, path: &Path) -> Result<Vec<Option<String>>> {
+        if !self.feature_powerset {
+            return Ok(vec![None]);
+        }
+        let manifest = Manifest::read(path)?;
+        let features = manifest.features();
+        let max = self.max_feature_count.unwrap_or(features.len());
+        let min = if self.skip_empty_features { 1 } else { 0 };
+        Ok(combinations(&features, max)
+            .into_iter()
+            .filter(|c| c.len() >= min)
+            .map(|c| Some(c.join(",")))
+            .collect())
+    }
+
+    fn run(&self, job: &Job) -> Result<Outcome> {
+        let started = Instant::now();
+        let path = job.dir.as_path();
+        let target = job.target.as_deref();
+        let mut args: Vec<String> = self.args.iter().map(|&a| a.to_owned()).collect();
         if args.is_empty() {
             bail!("Argument list empty");
         }
+        let extra_args_file = path.join(self.extra_args_file);
+        if extra_args_file.exists() {
+            let text = fs::read_to_string(&extra_args_file)
+                .with_context(|| format!("reading extra arguments from {:?}", extra_args_file))?;
+            let extra: Vec<&str> = text
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .collect();
+            insert_cargo_args(&mut args, &extra);
+        }
+        if self.artifact_sizes {
+            insert_cargo_args(&mut args, &["--message-format=json-render-diagnostics"]);
+        }
+        if let Some(target) = target {
+            insert_cargo_args(&mut args, &["--target", target]);
+        }
+        if self.locked
+            && !self.external
+            && (!self.locked_needs_lock_file || scan::has_cargo_lock(path)?)
+        {
+            insert_cargo_args(&mut args, &["--locked"]);
+        }
+        insert_cargo_args(&mut args, &self.target_selection);
+        if let Some(features) = &job.features {
+            insert_cargo_args(
+                &mut args,
+                &["--no-default-features", "--features", features],
+            );
+        } else if self.no_default_features && !self.external {
+            insert_cargo_args(&mut args, &["--no-default-features"]);
+        }
+        if self.respect_toolchain_file {
+            if let Some(toolchain) = read_toolchain_file(path)? {
+                args.insert(0, format!("+{}", toolchain));
+            }
+        }
         let mut cmd = if self.external {
             let cmd_str = args.remove(0);
             Command::new(cmd_str)
         } else {
             Command::new("cargo")
         };
+        // As with `Command::output`, unless a file is piped below
+        cmd.stdin(Stdio::null());
 
-        let output = cmd.args(&args).current_dir(path).output()?;
-        if self.output {
-            io::stdout().write_all(&output.stdout).unwrap();
-            io::stderr().write_all(&output.stderr).unwrap();
+        for var in &self.unset_env {
+            cmd.env_remove(var);
+        }
+        if self.cargo_env_passthrough {
+            cmd.envs(env::vars_os().filter(|(k, _)| k.to_string_lossy().starts_with("CARGO_")));
+        }
+        if self.fail_on_warning {
+            // Keep the inherited flags, unless removed
+            let inherited = env::var("RUSTFLAGS")
+                .ok()
+                .filter(|f| !f.trim().is_empty() && !self.unset_env.contains(&"RUSTFLAGS"));
+            let flags = match inherited {
+                Some(flags) => format!("{} -D warnings", flags),
+                None => "-D warnings".to_owned(),
+            };
+            cmd.env("RUSTFLAGS", flags);
         }
 
-        if self.exit_on_error && !output.status.success() {
-            if let Some(code) = output.status.code() {
-                bail!("Command returned a nonzero code {}", code);
+        if let Some(template) = self.stdin_file {
+            let mut stdin_path = template.replace("{path}", &path.to_string_lossy());
+            if stdin_path.contains("{name}") {
+                let manifest = Manifest::read(path)?;
+                let name = manifest.name().context("manifest has no package name")?;
+                stdin_path = stdin_path.replace("{name}", name);
+            }
+            if !Path::new(&stdin_path).exists() {
+                return Err(Skip {
+                    reason: format!("stdin file {:?} does not exist, skipping", stdin_path),
+                    fatal_with_exit: true,
+                }
+                .into());
+            }
+            let file = File::open(&stdin_path)
+                .with_context(|| format!("opening stdin file {:?}", stdin_path))?;
+            cmd.stdin(Stdio::from(file));
+        }
+
+        if self.no_color {
+            cmd.env("CARGO_TERM_COLOR", "never");
+            cmd.env("NO_COLOR", "1");
+        }
+
+        if !self.directory_env.is_empty() {
+            let absolute = scan::absolute(path)?;
+            for var in &self.directory_env {
+                cmd.env(var, &absolute);
+            }
+        }
+
+        if let Some(target) = target.filter(|&t| Some(t) == self.target_env) {
+            cmd.env("CARGO_BUILD_TARGET", target);
+        }
+
+        if self.cargo_env {
+            let manifest = Manifest::read(path)?;
+            let absolute = scan::absolute(path)?;
+            cmd.env("CARGO_MANIFEST_PATH", absolute.join("Cargo.toml"));
+            cmd.env("CARGO_MANIFEST_DIR", absolute);
+            cmd.envs(manifest.cargo_env());
+        }
+
+        if let Some(var) = self.workspace_root_env {
+            cmd.env(var, scan::find_workspace_root(path)?);
+        }
+
+        let working_dir = match self.working_dir {
+            Some(relative) => {
+                let dir = path.join(relative);
+                if !dir.is_dir() {
+                    return Err(Skip {
+                        reason: format!("working directory {:?} does not exist, skipping", dir),
+                        fatal_with_exit: false,
+                    }
+                    .into());
+                }
+                dir
+            }
+            None => path.to_owned(),
+        };
+
+        let timeout = match self.timeout_file.map(|name| path.join(name)) {
+            Some(file) if file.exists() => {
+                let text = fs::read_to_string(&file)
+                    .with_context(|| format!("reading timeout file {:?}", file))?;
+                let seconds: u64 = text
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("timeout in {:?} must be an integer", file))?;
+                Some(Duration::from_secs(seconds))
+            }
+            _ => None,
+        };
+
+        cmd.args(&args).current_dir(working_dir);
+        if self.print_env {
+            // A single write, so that parallel jobs don't interleave
+            eprint!("{}", describe_env(&cmd, &job.location()));
+        }
+        let stashed = self.git_stash && git_stash(path)?;
+        let output = if timeout.is_some() || self.measure_memory {
+            output_polled(&mut cmd, timeout, self.measure_memory)
+        } else {
+            cmd.output()
+                .map(|output| (output, None))
+                .map_err(anyhow::Error::from)
+        };
+        if stashed && self.git_stash_pop {
+            git(path, &["stash", "pop", "--quiet"])
+                .with_context(|| format!("restoring stashed changes in {:?}", path))?;
+        }
+        let (output, memory) = output?;
+
+        let mut outcome = Outcome {
+            status: if output.status.success() {
+                Status::Success
             } else {
-                bail!("Command returned an error");
+                Status::Failed(output.status.code())
+            },
+            stdout: output.stdout,
+            stderr: output.stderr,
+            artifacts: Vec::new(),
+            diagnostics: None,
+            duration: started.elapsed(),
+            memory,
+        };
+        if self.artifact_sizes {
+            // With artifact collection stdout is cargo's JSON messages, not for displaying
+            outcome.artifacts = artifacts::collect(&outcome.stdout);
+            outcome.stdout.clear();
+        }
+        if self.aggregate_json {
+            let manifest = scan::absolute(&path.join("Cargo.toml"))?;
+            outcome.stdout = tag_json_messages(&outcome.stdout, &manifest.to_string_lossy());
+        }
+        if self.verify_compile {
+            // Show the messages instead of the JSON records, and leave out cargo's progress
+            let diagnostics = diagnostics::collect(&outcome.stdout);
+            outcome.stdout = diagnostics.rendered.clone().into_bytes();
+            if outcome.status == Status::Success {
+                outcome.stderr.clear();
+            }
+            outcome.diagnostics = Some(diagnostics);
+        }
+        Ok(outcome)
+    }
+}
+
+/// Reason for not running a job, which is only a warning and not recorded as a failure
+#[derive(Debug)]
+struct Skip {
+    reason: String,
+    /// With `--exit` this is an error instead
+    fatal_with_exit: bool,
+}
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+impl std::error::Error for Skip {}
+
+/// Whether `e` is a `Skip` that doesn't count as an error
+fn is_skip(e: &anyhow::Error, exit_on_error: bool) -> bool {
+    e.downcast_ref::<Skip>()
+        .is_some_and(|skip| !(skip.fatal_with_exit && exit_on_error))
+}
+
+/// Result of running the command in a directory
+#[derive(Debug)]
+struct Outcome {
+    status: Status,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    /// Binaries and cdylibs built, if collected
+    artifacts: Vec<artifacts::Artifact>,
+    /// Compiler messages, if collected
+    diagnostics: Option<diagnostics::Diagnostics>,
+    /// Wall-clock time the command took
+    duration: Duration,
+    /// Peak memory usage of the command process, if measured
+    memory: Option<MemoryUsage>,
+}
+
+/// Write an `--emit-ndjson` event to stdout, with the job it is about and `fields`
+fn emit_event(kind: &str, job: Option<&Job>, fields: Vec<(&str, json::Value)>) {
+    let mut pairs = vec![("event", kind.into())];
+    if let Some(job) = job {
+        pairs.push(("path", job.dir.to_string_lossy().into_owned().into()));
+        pairs.push(("target", job.target.clone().into()));
+        pairs.push(("features", job.features.clone().into()));
+    }
+    pairs.extend(fields);
+    let mut stdout = io::stdout().lock();
+    let written = writeln!(stdout, "{}", json::Value::object(pairs)).and_then(|_| stdout.flush());
+    match written {
+        Ok(()) => {}
+        // The reader is gone, e.g. `| head`, so nobody is interested in the rest of the run.
+        // Exit like a process killed by SIGPIPE would.
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => exit(141),
+        Err(e) => panic!("writing to stdout: {}", e),
+    }
+}
+
+/// The environment `cmd` will run with, with the changes made to the inherited one marked
+fn describe_env(cmd: &Command, location: &str) -> String {
+    let mut vars: BTreeMap<String, (char, Option<String>)> = env::vars_os()
+        .map(|(k, v)| {
+            let value = v.to_string_lossy().into_owned();
+            (k.to_string_lossy().into_owned(), (' ', Some(value)))
+        })
+        .collect();
+    for (key, value) in cmd.get_envs() {
+        let key = key.to_string_lossy().into_owned();
+        let value = value.map(|v| v.to_string_lossy().into_owned());
+        let mark = if value.is_some() { '+' } else { '-' };
+        vars.insert(key, (mark, value));
+    }
+
+    let mut text = format!("Environment of the command in {}:\n", location);
+    for (key, (mark, value)) in vars {
+        match value {
+            Some(value) => text += &format!("  {} {}={}\n", mark, key, value),
+            None => text += &format!("  {} {}\n", mark, key),
+        }
+    }
+    text
+}
+
+/// Like `Command::output`, but kills the command if it doesn't finish within `timeout`,
+/// and samples its memory usage while it runs if `measure_memory` is set
+fn output_polled(
+    cmd: &mut Command,
+    timeout: Option<Duration>,
+    measure_memory: bool,
+) -> Result<(Output, Option<MemoryUsage>)> {
+    // With a timeout the command gets its own process group, so that everything it started
+    // can be killed with it, e.g. a hung test binary under `cargo test`
+    #[cfg(unix)]
+    if timeout.is_some() {
+        std::os::unix::process::CommandExt::process_group(cmd, 0);
+    }
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+
+    // Read the pipes in the background so that the command can't block on a full pipe
+    let mut stdout = child.stdout.take().expect("stdout not piped");
+    let mut stderr = child.stderr.take().expect("stderr not piped");
+    let stdout = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = stdout.read_to_end(&mut buffer);
+        buffer
+    });
+    let stderr = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = stderr.read_to_end(&mut buffer);
+        buffer
+    });
+
+    let start = Instant::now();
+    let mut memory: Option<MemoryUsage> = None;
+    let status = loop {
+        if measure_memory {
+            // Processes come and go, so keep the largest peaks seen
+            if let Some(sample) = memory::sample(child.id()) {
+                memory = Some(memory.unwrap_or_default().max(sample));
+            }
+        }
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if let Some(timeout) = timeout.filter(|&t| start.elapsed() >= t) {
+            kill_group(&mut child)?;
+            child.wait()?;
+            bail!("Command timed out after {} seconds", timeout.as_secs());
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let output = Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    };
+    Ok((output, memory))
+}
+
+/// Kill `child` and the rest of its process group, which it leads when started with a timeout
+fn kill_group(child: &mut Child) -> io::Result<()> {
+    #[cfg(unix)]
+    // SAFETY: `kill` has no memory safety requirements, a negative pid means the process group
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    child.kill()
+}
+
+/// Add `manifest_path` to each JSON object line of `stdout` that doesn't have it,
+/// leaving out lines that aren't JSON objects
+fn tag_json_messages(stdout: &[u8], manifest_path: &str) -> Vec<u8> {
+    let mut result = Vec::new();
+    for line in String::from_utf8_lossy(stdout).lines() {
+        if let Ok(json::Value::Object(mut pairs)) = json::parse(line) {
+            if !pairs.iter().any(|(k, _)| k == "manifest_path") {
+                pairs.push(("manifest_path".to_owned(), manifest_path.into()));
+            }
+            result.extend(format!("{}\n", json::Value::Object(pairs)).bytes());
+        }
+    }
+    result
+}
+
+/// All combinations of at most `max` items, smallest first
+fn combinations<'a>(items: &[&'a str], max: usize) -> Vec<Vec<&'a str>> {
+    let mut result = vec![Vec::new()];
+    let mut previous = vec![(Vec::new(), 0)];
+    for _ in 0..max.min(items.len()) {
+        // Extend each combination of the previous size with the items after its last one
+        let mut next = Vec::new();
+        for (combination, start) in &previous {
+            for (i, item) in items.iter().enumerate().skip(*start) {
+                let mut c: Vec<&str> = combination.clone();
+                c.push(*item);
+                next.push((c, i + 1));
+            }
+        }
+        result.extend(next.iter().map(|(c, _)| c.clone()));
+        previous = next;
+    }
+    result
+}
+
+/// Add arguments to a cargo command, before any `--` separating the arguments
+/// passed through to the program run by cargo
+fn insert_cargo_args(args: &mut Vec<String>, extra: &[&str]) {
+    let index = args.iter().position(|a| a == "--").unwrap_or(args.len());
+    for (i, e) in extra.iter().enumerate() {
+        args.insert(index + i, (*e).to_owned());
+    }
+}
+
+/// Read the toolchain channel from `rust-toolchain.toml` or the legacy `rust-toolchain` file
+/// Whether `defaults` from config.toml conflict with the arguments given on the command line,
+/// e.g. `exit = true` with `--no-exit`
+fn conflicts_with_given(app: &App, args: &[String], defaults: &[String]) -> bool {
+    let mut all = vec![args[0].clone()];
+    all.extend(defaults.iter().cloned());
+    all.extend(args[1..].iter().cloned());
+    matches!(
+        app.clone().get_matches_from_safe(&all),
+        Err(e) if e.kind == ErrorKind::ArgumentConflict
+    )
+}
+
+fn read_toolchain_file(dir: &Path) -> Result<Option<String>> {
+    for name in &["rust-toolchain.toml", "rust-toolchain"] {
+        let path = dir.join(name);
+        if !path.exists() {
+            continue;
+        }
+        let text = fs::read_to_string(&path).with_context(|| format!("reading {:?}", path))?;
+        let text = text.trim();
+
+        // The legacy file may contain just the channel name
+        if !text.contains('[') {
+            return Ok(Some(text.to_owned()));
+        }
+        let doc: toml_edit::DocumentMut = text
+            .parse()
+            .with_context(|| format!("parsing {:?}", path))?;
+        return match manifest::get_path(doc.as_item(), "toolchain.channel")
+            .and_then(toml_edit::Item::as_str)
+        {
+            Some(channel) => Ok(Some(channel.to_owned())),
+            None => bail!("{:?} has no toolchain.channel", path),
+        };
+    }
+    Ok(None)
+}
+
+/// Apply `--set-manifest-field` updates to the manifests in `dirs`,
+/// only printing a diff of the changes on a dry run
+fn update_manifests(
+    dirs: &[PathBuf],
+    updates: &[FieldUpdate],
+    dry_run: bool,
+    verbose: bool,
+    exit_on_error: bool,
+) -> Result<()> {
+    if updates.is_empty() {
+        return Ok(());
+    }
+    for dir in dirs {
+        match patch::update_manifest(dir, updates, !dry_run) {
+            Ok(Some((old, new))) => {
+                let name = dir.join("Cargo.toml");
+                if dry_run {
+                    print!(
+                        "{}",
+                        patch::unified_diff(&name.to_string_lossy(), &old, &new)
+                    );
+                } else if verbose {
+                    eprintln!("Updated {}", name.display());
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                if exit_on_error {
+                    return Err(e);
+                }
+                warn(&e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Make sure that no two crates in `dirs` share a package name
+fn check_unique_names(dirs: &[PathBuf]) -> Result<()> {
+    let mut names: BTreeMap<String, Vec<&Path>> = BTreeMap::new();
+    for dir in dirs {
+        match Manifest::read(dir) {
+            Ok(manifest) => {
+                if let Some(name) = manifest.name() {
+                    names.entry(name.to_owned()).or_default().push(dir);
+                }
+            }
+            Err(e) => warn(&e),
+        }
+    }
+
+    let mut duplicates = 0;
+    for (name, dirs) in &names {
+        if dirs.len() > 1 {
+            duplicates += 1;
+            eprintln!("Package name '{}' is used by:", name);
+            for dir in dirs {
+                eprintln!("    {}", dir.display());
+            }
+        }
+    }
+    if duplicates != 0 {
+        bail!("{} package names are not unique", duplicates);
+    }
+    Ok(())
+}
+
+/// Fail if the path dependencies between the crates in `dirs` have cycles, printing each of them
+fn check_package_graph(dirs: &[PathBuf]) -> Result<()> {
+    let nodes: Vec<PathBuf> = dirs
+        .iter()
+        .map(|d| scan::absolute(d))
+        .collect::<Result<_>>()?;
+    let mut edges: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
+    for (i, dir) in nodes.iter().enumerate() {
+        let manifest = match Manifest::read(dir) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                warn(&e);
+                continue;
+            }
+        };
+        for path in manifest.path_dependencies() {
+            let dependency = scan::absolute(&dir.join(path))?;
+            if let Some(j) = nodes.iter().position(|n| *n == dependency) {
+                edges[i].push(j);
             }
         }
-        Ok(())
     }
+
+    // Depth-first search, a dependency that is still on the stack closes a cycle
+    let mut cycles: Vec<Vec<usize>> = Vec::new();
+    let mut done = vec![false; nodes.len()];
+    for start in 0..nodes.len() {
+        if done[start] {
+            continue;
+        }
+        // Nodes on the current path, with the index of their next edge to follow
+        let mut stack = vec![(start, 0)];
+        while let Some((node, edge)) = stack.last_mut() {
+            let node = *node;
+            match edges[node].get(*edge) {
+                Some(&next) => {
+                    *edge += 1;
+                    if let Some(pos) = stack.iter().position(|&(n, _)| n == next) {
+                        let mut cycle: Vec<usize> = stack[pos..].iter().map(|&(n, _)| n).collect();
+                        cycle.push(next);
+                        cycles.push(cycle);
+                    } else if !done[next] {
+                        stack.push((next, 0));
+                    }
+                }
+                None => {
+                    done[node] = true;
+                    stack.pop();
+                }
+            }
+        }
+    }
+
+    for cycle in &cycles {
+        let names: Vec<String> = cycle
+            .iter()
+            .map(|&i| nodes[i].display().to_string())
+            .collect();
+        eprintln!("Dependency cycle:\n    {}", names.join("\n -> "));
+    }
+    if !cycles.is_empty() {
+        bail!("{} dependency cycles found", cycles.len());
+    }
+    Ok(())
+}
+
+/// Command that runs `command` using the system shell
+fn shell_command(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.args(["/C", command]);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", command]);
+        cmd
+    }
+}
+
+/// Summary of `git diff <reference>` limited to `dir`, e.g. `2 files changed, 4 insertions(+)`
+fn git_diff_summary(dir: &Path, reference: &str) -> Result<String> {
+    let output = Command::new("git")
+        .args(["diff", "--shortstat", reference, "--", "."])
+        .current_dir(dir)
+        .output()
+        .context("running git diff")?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let summary = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    if summary.is_empty() {
+        Ok("no changes".to_owned())
+    } else {
+        Ok(summary)
+    }
+}
+
+/// Stash the uncommitted changes to tracked files in `dir`, returning whether there were any.
+/// Directories outside git repositories are left as they are.
+fn git_stash(dir: &Path) -> Result<bool> {
+    let status = Command::new("git")
+        .args(["status", "--porcelain", "--untracked-files=no", "--", "."])
+        .current_dir(dir)
+        .output()
+        .context("running git status")?;
+    if !status.status.success() || status.stdout.is_empty() {
+        return Ok(false);
+    }
+    git(dir, &["stash", "push", "--quiet", "--", "."])
+        .with_context(|| format!("stashing changes in {:?}", dir))?;
+    Ok(true)
+}
+
+/// Run git in `dir`, failing with its stderr if it fails
+fn git(dir: &Path, args: &[&str]) -> Result<()> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .context("running git")?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+/// Alias definitions for common subcommands, as `abbr` for fish and `alias` for others
+fn shell_aliases(shell: &str) -> String {
+    let mut text = String::new();
+    for command in ["build", "test", "check"] {
+        text += &match shell {
+            "fish" => format!("abbr --add cr{} 'cargo recursive {}'\n", command, command),
+            _ => format!("alias cr{}='cargo recursive {}'\n", command, command),
+        };
+    }
+    text
+}
+
+/// Run a hook command in `dir`, its exit code is ignored
+fn run_hook(command: &str, dir: &Path) -> Result<Output> {
+    shell_command(command)
+        .current_dir(dir)
+        .output()
+        .with_context(|| format!("running {:?}", command))
 }
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use toml_edit::Item;
+
+use crate::manifest::Manifest;
+use crate::scan;
+
+/// A crate as a Makefile target
+#[derive(Debug)]
+struct Target {
+    dir: PathBuf,
+    /// Package names of the crate's `[dependencies]`
+    dependencies: Vec<String>,
+}
+
+/// Write a Makefile to `output` with a phony target for each crate in `dirs`,
+/// named after the package and depending on the targets of its local dependencies.
+/// Each recipe runs `command` in the crate's directory.
+pub fn generate(dirs: &[PathBuf], command: &[String], output: &Path, verbose: bool) -> Result<()> {
+    let mut targets: BTreeMap<String, Target> = BTreeMap::new();
+    for dir in dirs {
+        let manifest = match Manifest::read(dir) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                crate::warn(&e);
+                continue;
+            }
+        };
+        let name = match manifest.name() {
+            Some(name) => name.to_owned(),
+            None => {
+                if verbose {
+                    eprintln!("Skipping {:?}: no package name", dir);
+                }
+                continue;
+            }
+        };
+        if let Some(existing) = targets.get(&name) {
+            crate::warn(&anyhow::anyhow!(
+                "package name '{}' is used by both {:?} and {:?}, skipping the latter",
+                name,
+                existing.dir,
+                dir
+            ));
+            continue;
+        }
+        targets.insert(
+            name,
+            Target {
+                dir: scan::absolute(dir)?,
+                dependencies: dependency_names(&manifest),
+            },
+        );
+    }
+
+    let recipe = command
+        .iter()
+        .map(|a| escape_make(&quote_shell(a)))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let names: Vec<&str> = targets.keys().map(String::as_str).collect();
+
+    let mut text = "# Generated by cargo-recursive\n\n".to_owned();
+    text += &format!(".PHONY: all {}\n\n", names.join(" "));
+    text += &format!("all: {}\n", names.join(" "));
+    for (name, target) in &targets {
+        let local: Vec<&str> = target
+            .dependencies
+            .iter()
+            .map(String::as_str)
+            .filter(|d| *d != name && targets.contains_key(*d))
+            .collect();
+        text += &format!("\n{}:", name);
+        for d in local {
+            text += &format!(" {}", d);
+        }
+        text += &format!(
+            "\n\tcd {} && {}\n",
+            escape_make(&quote_shell(&target.dir.to_string_lossy())),
+            recipe
+        );
+    }
+
+    fs::write(output, text).with_context(|| format!("writing {:?}", output))
+}
+
+/// Package names in `[dependencies]`, following `package` renames
+fn dependency_names(manifest: &Manifest) -> Vec<String> {
+    let table = match manifest.get("dependencies").and_then(Item::as_table_like) {
+        Some(table) => table,
+        None => return Vec::new(),
+    };
+    table
+        .iter()
+        .map(|(key, value)| {
+            value
+                .as_table_like()
+                .and_then(|t| t.get("package"))
+                .and_then(Item::as_str)
+                .unwrap_or(key)
+                .to_owned()
+        })
+        .collect()
+}
+
+/// Quote an argument for `sh` if it contains anything special
+fn quote_shell(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+    if plain {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Escape `$` which make would otherwise expand
+fn escape_make(text: &str) -> String {
+    text.replace('$', "$$")
+}
@@ -0,0 +1,138 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use toml_edit::{DocumentMut, Item, TableLike, Value};
+
+/// Parsed `Cargo.toml`
+#[derive(Debug)]
+pub struct Manifest {
+    pub doc: DocumentMut,
+}
+impl Manifest {
+    /// Read the manifest of the crate in `dir`
+    pub fn read(dir: &Path) -> Result<Self> {
+        let path = dir.join("Cargo.toml");
+        let text = fs::read_to_string(&path).with_context(|| format!("reading {:?}", path))?;
+        let doc = text
+            .parse()
+            .with_context(|| format!("parsing {:?}", path))?;
+        Ok(Self { doc })
+    }
+
+    /// Look up a dotted key, e.g. `package.version`
+    pub fn get(&self, path: &str) -> Option<&Item> {
+        get_path(self.doc.as_item(), path)
+    }
+
+    /// Package name, `None` for virtual manifests
+    pub fn name(&self) -> Option<&str> {
+        self.get("package.name").and_then(Item::as_str)
+    }
+
+    /// Environment variables cargo sets for the package when building it, e.g. `CARGO_PKG_NAME`.
+    /// Fields inherited from the workspace are left out.
+    pub fn cargo_env(&self) -> Vec<(&'static str, String)> {
+        let field = |key: &str| self.get(&format!("package.{}", key)).and_then(Item::as_str);
+        let mut env = Vec::new();
+        for (var, key) in &[
+            ("CARGO_PKG_NAME", "name"),
+            ("CARGO_PKG_DESCRIPTION", "description"),
+            ("CARGO_PKG_HOMEPAGE", "homepage"),
+            ("CARGO_PKG_REPOSITORY", "repository"),
+            ("CARGO_PKG_LICENSE", "license"),
+            ("CARGO_PKG_LICENSE_FILE", "license-file"),
+            ("CARGO_PKG_README", "readme"),
+            ("CARGO_PKG_RUST_VERSION", "rust-version"),
+        ] {
+            if let Some(value) = field(key) {
+                env.push((*var, value.to_owned()));
+            }
+        }
+        if let Some(version) = field("version") {
+            env.push(("CARGO_PKG_VERSION", version.to_owned()));
+            let (version, pre) = version.split_once('-').unwrap_or((version, ""));
+            let version = version.split('+').next().unwrap_or_default();
+            let mut parts = version.split('.');
+            for var in &[
+                "CARGO_PKG_VERSION_MAJOR",
+                "CARGO_PKG_VERSION_MINOR",
+                "CARGO_PKG_VERSION_PATCH",
+            ] {
+                env.push((*var, parts.next().unwrap_or_default().to_owned()));
+            }
+            let pre = pre.split('+').next().unwrap_or_default();
+            env.push(("CARGO_PKG_VERSION_PRE", pre.to_owned()));
+        }
+        if let Some(authors) = self.get("package.authors").and_then(Item::as_array) {
+            let authors: Vec<&str> = authors.iter().filter_map(Value::as_str).collect();
+            env.push(("CARGO_PKG_AUTHORS", authors.join(":")));
+        }
+        env
+    }
+
+    /// Names of the features declared in `[features]`, except `default`
+    pub fn features(&self) -> Vec<&str> {
+        match self.get("features").and_then(Item::as_table_like) {
+            Some(table) => table
+                .iter()
+                .map(|(name, _)| name)
+                .filter(|name| *name != "default")
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// `path` values of normal and build dependencies, including target specific ones.
+    /// Dev-dependencies are left out, as cargo allows cycles through them.
+    pub fn path_dependencies(&self) -> Vec<&str> {
+        let mut tables: Vec<&dyn TableLike> = Vec::new();
+        if let Some(targets) = self.get("target").and_then(Item::as_table_like) {
+            tables.extend(targets.iter().filter_map(|(_, t)| t.as_table_like()));
+        }
+        tables.push(self.doc.as_table());
+
+        let mut paths = Vec::new();
+        for table in tables {
+            for kind in ["dependencies", "build-dependencies"] {
+                if let Some(deps) = table.get(kind).and_then(Item::as_table_like) {
+                    paths.extend(deps.iter().filter_map(|(_, dep)| {
+                        dep.as_table_like()?.get("path").and_then(Item::as_str)
+                    }));
+                }
+            }
+        }
+        paths
+    }
+
+    /// Whether publishing isn't disabled with `publish = false` or `publish = []`
+    pub fn publishable(&self) -> bool {
+        match self.get("package.publish").and_then(Item::as_value) {
+            Some(Value::Boolean(b)) => *b.value(),
+            Some(Value::Array(registries)) => !registries.is_empty(),
+            _ => true,
+        }
+    }
+
+    /// Whether `cargo publish` would be allowed to publish this to crates.io,
+    /// based on `package.publish`
+    pub fn publishes_to_crates_io(&self) -> bool {
+        if self.name().is_none() {
+            return false;
+        }
+        match self.get("package.publish").and_then(Item::as_value) {
+            Some(Value::Boolean(b)) => *b.value(),
+            Some(Value::Array(registries)) => {
+                registries.iter().any(|r| r.as_str() == Some("crates-io"))
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Look up a dotted key, e.g. `toolchain.channel`, in tables and inline tables
+pub fn get_path<'a>(item: &'a Item, path: &str) -> Option<&'a Item> {
+    path.split('.')
+        .try_fold(item, |item, key| item.as_table_like()?.get(key))
+}
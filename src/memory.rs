@@ -0,0 +1,90 @@
+/// Peak memory usage of a process, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MemoryUsage {
+    /// Peak virtual memory size, `VmPeak`
+    pub virtual_peak: u64,
+    /// Peak resident set size, `VmHWM`
+    pub rss_peak: u64,
+}
+impl MemoryUsage {
+    /// Combine with a later sample of the same process
+    pub fn max(self, other: Self) -> Self {
+        Self {
+            virtual_peak: self.virtual_peak.max(other.virtual_peak),
+            rss_peak: self.rss_peak.max(other.rss_peak),
+        }
+    }
+}
+
+/// Peak memory usage of the largest of a running process and its descendants,
+/// like `ru_maxrss` of `wait4` but also for virtual memory.
+/// The values are read from `/proc/<pid>/status`, so they are gone once a process exits
+/// and this has to be sampled while the command runs. Returns `None` if nothing could be read.
+#[cfg(target_os = "linux")]
+pub fn sample(pid: u32) -> Option<MemoryUsage> {
+    descendants(pid)
+        .into_iter()
+        .filter_map(process_peak)
+        .reduce(MemoryUsage::max)
+}
+
+/// `pid` and all processes below it, from the parent pids in `/proc/<pid>/stat`
+#[cfg(target_os = "linux")]
+fn descendants(pid: u32) -> Vec<u32> {
+    let mut parents = Vec::new();
+    for e in std::fs::read_dir("/proc").into_iter().flatten().flatten() {
+        let child = match e.file_name().to_str().and_then(|n| n.parse::<u32>().ok()) {
+            Some(child) => child,
+            None => continue,
+        };
+        let stat = match std::fs::read_to_string(e.path().join("stat")) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        // The command name in parentheses may contain spaces, the state and parent follow it
+        let parent = stat
+            .rsplit_once(')')
+            .and_then(|(_, rest)| rest.split_whitespace().nth(1)?.parse::<u32>().ok());
+        if let Some(parent) = parent {
+            parents.push((parent, child));
+        }
+    }
+
+    let mut result = vec![pid];
+    let mut i = 0;
+    while i < result.len() {
+        let current = result[i];
+        result.extend(
+            parents
+                .iter()
+                .filter(|(p, _)| *p == current)
+                .map(|(_, c)| *c),
+        );
+        i += 1;
+    }
+    result
+}
+
+/// `VmPeak` and `VmHWM` of a single process
+#[cfg(target_os = "linux")]
+fn process_peak(pid: u32) -> Option<MemoryUsage> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let kilobytes = |key: &str| -> Option<u64> {
+        let line = status.lines().find(|l| l.starts_with(key))?;
+        line[key.len()..]
+            .trim()
+            .trim_end_matches("kB")
+            .trim()
+            .parse()
+            .ok()
+    };
+    Some(MemoryUsage {
+        virtual_peak: kilobytes("VmPeak:")? * 1024,
+        rss_peak: kilobytes("VmHWM:")? * 1024,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn sample(_pid: u32) -> Option<MemoryUsage> {
+    None
+}
@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::report::Status;
+
+/// Collapsible log sections supported by CI systems
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogGroups {
+    None,
+    GitHub,
+    GitLab,
+}
+impl LogGroups {
+    /// Parse the `--log-groups` value, detecting the CI system in `auto` mode
+    pub fn from_arg(value: &str) -> Self {
+        match value {
+            "github" => LogGroups::GitHub,
+            "gitlab" => LogGroups::GitLab,
+            "auto" if env::var("GITHUB_ACTIONS").as_deref() == Ok("true") => LogGroups::GitHub,
+            "auto" if env::var_os("GITLAB_CI").is_some() => LogGroups::GitLab,
+            _ => LogGroups::None,
+        }
+    }
+}
+
+/// Prints the output of each run, wrapped in a log group if enabled
+#[derive(Debug)]
+pub struct Printer {
+    pub log_groups: LogGroups,
+    /// Number of groups printed so far, used for unique GitLab section names
+    sections: usize,
+    /// Write everything here instead of stdout and stderr
+    file: Option<File>,
+    /// Remove ANSI escape sequences from the output of the commands
+    pub strip_ansi: bool,
+    /// Hold back stderr of failed runs until `print_errors` is called
+    pub group_errors: bool,
+    /// Titles and stderr of failed runs, when grouping errors
+    errors: Vec<(String, Vec<u8>)>,
+    /// Print the stderr of failed runs only once for identical error messages
+    pub deduplicate_errors: bool,
+    /// Index in `repeated_errors` by error message
+    seen_errors: HashMap<Vec<u8>, usize>,
+    /// Title of the first run with each error message and how many other runs had it
+    repeated_errors: Vec<(String, usize)>,
+    /// Show a progress line on stderr
+    pub progress: bool,
+    /// Whether the progress line is currently displayed
+    progress_shown: bool,
+}
+impl Printer {
+    pub fn new(log_groups: LogGroups, file: Option<File>) -> Self {
+        Self {
+            log_groups,
+            sections: 0,
+            file,
+            strip_ansi: false,
+            group_errors: false,
+            errors: Vec::new(),
+            deduplicate_errors: false,
+            seen_errors: HashMap::new(),
+            repeated_errors: Vec::new(),
+            progress: false,
+            progress_shown: false,
+        }
+    }
+
+    /// Replace the progress line, if enabled
+    pub fn show_progress(&mut self, message: &str) {
+        if self.progress {
+            eprint!("\r\x1b[K{}", message);
+            self.progress_shown = true;
+        }
+    }
+
+    /// Remove the progress line so that other output can be printed
+    pub fn clear_progress(&mut self) {
+        if self.progress_shown {
+            eprint!("\r\x1b[K");
+            self.progress_shown = false;
+        }
+    }
+
+    /// Print a message of this tool, e.g. verbose output
+    pub fn note(&mut self, message: &str) {
+        self.clear_progress();
+        match &mut self.file {
+            Some(f) => {
+                writeln!(f, "{}", message).unwrap();
+                f.flush().unwrap();
+            }
+            None => eprintln!("{}", message),
+        }
+    }
+
+    /// Print output of a single run titled `title`
+    pub fn print_output(&mut self, title: &str, status: Status, stdout: &[u8], stderr: &[u8]) {
+        self.clear_progress();
+        let failed = matches!(status, Status::Failed(_));
+        let replaced;
+        let first = if failed {
+            self.repeated_error(title, stderr)
+        } else {
+            None
+        };
+        let stderr = match first {
+            Some(first) => {
+                replaced = format!("(same error as {})\n", first).into_bytes();
+                &replaced[..]
+            }
+            _ => stderr,
+        };
+        let stderr = if self.group_errors && failed {
+            let held = if self.strip_ansi {
+                strip_ansi(stderr)
+            } else {
+                stderr.to_vec()
+            };
+            self.errors.push((title.to_owned(), held));
+            &[]
+        } else {
+            stderr
+        };
+        // Log group markers are only meant for CI logs, not for files
+        if self.file.is_some() {
+            self.print_streams(stdout, stderr);
+            return;
+        }
+
+        let title = match status {
+            Status::Failed(Some(code)) => format!("{} (exit code {})", title, code),
+            Status::Failed(None) => format!("{} (failed)", title),
+            _ => title.to_owned(),
+        };
+
+        self.sections += 1;
+        let section = format!("project_{}", self.sections);
+        match self.log_groups {
+            LogGroups::None => {}
+            LogGroups::GitHub => println!("::group::{}", title),
+            LogGroups::GitLab => println!(
+                "\x1b[0Ksection_start:{}:{}[collapsed=true]\r\x1b[0K{}",
+                timestamp(),
+                section,
+                title
+            ),
+        }
+
+        self.print_streams(stdout, stderr);
+
+        match self.log_groups {
+            LogGroups::None => {}
+            LogGroups::GitHub => println!("::endgroup::"),
+            LogGroups::GitLab => {
+                println!("\x1b[0Ksection_end:{}:{}\r\x1b[0K", timestamp(), section)
+            }
+        }
+    }
+
+    /// Print captured output as is, without a log group
+    pub fn print_streams(&mut self, stdout: &[u8], stderr: &[u8]) {
+        self.clear_progress();
+        let (stdout, stderr) = if self.strip_ansi {
+            (strip_ansi(stdout), strip_ansi(stderr))
+        } else {
+            (stdout.to_vec(), stderr.to_vec())
+        };
+        match &mut self.file {
+            Some(f) => {
+                f.write_all(&stdout).unwrap();
+                f.write_all(&stderr).unwrap();
+                f.flush().unwrap();
+            }
+            None => {
+                io::stdout().write_all(&stdout).unwrap();
+                io::stdout().flush().unwrap();
+                io::stderr().write_all(&stderr).unwrap();
+            }
+        }
+    }
+
+    /// With `deduplicate_errors`, record the error message of a failed run.
+    /// Returns the title of the first run if the same message was seen before.
+    fn repeated_error(&mut self, title: &str, stderr: &[u8]) -> Option<String> {
+        if !self.deduplicate_errors {
+            return None;
+        }
+        let key = error_message(stderr);
+        match self.seen_errors.get(&key) {
+            Some(&index) => {
+                let (first, count) = &mut self.repeated_errors[index];
+                *count += 1;
+                Some(first.clone())
+            }
+            None => {
+                self.seen_errors.insert(key, self.repeated_errors.len());
+                self.repeated_errors.push((title.to_owned(), 0));
+                None
+            }
+        }
+    }
+
+    /// Print the stderr of all failed runs held back by `group_errors`,
+    /// and how often each error was repeated with `deduplicate_errors`
+    pub fn print_errors(&mut self) {
+        self.clear_progress();
+        let repeated: Vec<_> = self
+            .repeated_errors
+            .drain(..)
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        if self.errors.is_empty() && repeated.is_empty() {
+            return;
+        }
+        let mut text = Vec::new();
+        if !self.errors.is_empty() {
+            text.extend("\n──── Errors ────\n".bytes());
+        }
+        for (title, stderr) in self.errors.drain(..) {
+            text.extend(format!("{}:\n", title).bytes());
+            text.extend(stderr);
+        }
+        for (title, count) in repeated {
+            let crates = if count == 1 { "crate" } else { "crates" };
+            text.extend(
+                format!(
+                    "{}: (this error appeared in {} other {})\n",
+                    title, count, crates
+                )
+                .bytes(),
+            );
+        }
+        match &mut self.file {
+            Some(f) => {
+                f.write_all(&text).unwrap();
+                f.flush().unwrap();
+            }
+            None => io::stderr().write_all(&text).unwrap(),
+        }
+    }
+}
+
+/// Summary lines cargo prints after the diagnostics, they name the crate or count its errors
+const SUMMARY_PREFIXES: [&[u8]; 2] = [b"error: could not compile", b"error: aborting due to"];
+
+/// The lines of `stderr` starting with `error`, or all of it if there are none.
+/// Other lines often name the crate, so they would make every message unique.
+fn error_message(stderr: &[u8]) -> Vec<u8> {
+    let stderr = strip_ansi(stderr);
+    let lines: Vec<&[u8]> = stderr
+        .split(|&b| b == b'\n')
+        .filter(|line| line.starts_with(b"error"))
+        .filter(|line| !SUMMARY_PREFIXES.iter().any(|p| line.starts_with(p)))
+        .collect();
+    if lines.is_empty() {
+        stderr
+    } else {
+        lines.join(&b'\n')
+    }
+}
+
+/// Remove ANSI escape sequences, e.g. colors
+pub fn strip_ansi(input: &[u8]) -> Vec<u8> {
+    enum State {
+        Text,
+        /// After ESC
+        Escape,
+        /// Control sequence, ends with a byte in range 0x40..=0x7e
+        Csi,
+        /// Operating system command, ends with BEL or ESC \
+        Osc,
+        /// ESC inside an operating system command
+        OscEscape,
+    }
+
+    let mut result = Vec::with_capacity(input.len());
+    let mut state = State::Text;
+    for &b in input {
+        state = match state {
+            State::Text if b == 0x1b => State::Escape,
+            State::Text => {
+                result.push(b);
+                State::Text
+            }
+            State::Escape if b == b'[' => State::Csi,
+            State::Escape if b == b']' => State::Osc,
+            State::Escape => State::Text,
+            State::Csi if (0x40..=0x7e).contains(&b) => State::Text,
+            State::Csi => State::Csi,
+            State::Osc if b == 0x07 => State::Text,
+            State::Osc if b == 0x1b => State::OscEscape,
+            State::Osc => State::Osc,
+            State::OscEscape if b == b'\\' => State::Text,
+            State::OscEscape => State::Osc,
+        };
+    }
+    result
+}
+
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cargo_stderr(krate: &str) -> String {
+        format!(
+            "   Compiling {krate} v0.1.0 (/work/{krate})\n\
+             \x1b[1m\x1b[31merror[E0432]\x1b[0m\x1b[1m: unresolved import `missing_dep`\x1b[0m\n\
+             \x20--> src/lib.rs:1:5\n\
+             \x20 |\n\
+             1 | use missing_dep::x;\n\
+             \x20 |     ^^^^^^^^^^^ use of unresolved module or unlinked crate `missing_dep`\n\
+             \n\
+             For more information about this error, try `rustc --explain E0432`.\n\
+             error: could not compile `{krate}` (lib) due to 1 previous error\n",
+            krate = krate
+        )
+    }
+
+    #[test]
+    fn error_message_ignores_crate_specific_lines() {
+        let a = error_message(cargo_stderr("a").as_bytes());
+        let b = error_message(cargo_stderr("b").as_bytes());
+        assert_eq!(a, b);
+        assert_eq!(a, b"error[E0432]: unresolved import `missing_dep`");
+    }
+
+    #[test]
+    fn error_message_distinguishes_different_errors() {
+        let other = cargo_stderr("b").replace("missing_dep", "other_dep");
+        assert_ne!(
+            error_message(cargo_stderr("a").as_bytes()),
+            error_message(other.as_bytes())
+        );
+    }
+
+    #[test]
+    fn error_message_falls_back_to_whole_output() {
+        assert_eq!(error_message(b"segfault\n"), b"segfault\n");
+    }
+}
@@ -0,0 +1,266 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use toml_edit::{DocumentMut, InlineTable, Item, Table, Value};
+
+/// A `--set-manifest-field` assignment
+#[derive(Debug, Clone)]
+pub struct FieldUpdate {
+    /// Dotted key, e.g. `package.version`
+    pub key: Vec<String>,
+    pub value: Value,
+}
+impl FieldUpdate {
+    /// Parse `KEY=VALUE`. The value is a string unless it is `true`, `false`,
+    /// quoted, an array or an inline table, which are read as TOML.
+    /// So `publish=false` sets a boolean, but `version=1.0` sets the string `"1.0"`.
+    pub fn parse(arg: &str) -> Result<Self> {
+        let (key, value) = arg
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected KEY=VALUE, got {:?}", arg))?;
+        let key: Vec<String> = key.trim().split('.').map(str::to_owned).collect();
+        if key.iter().any(String::is_empty) {
+            bail!("invalid key in {:?}", arg);
+        }
+        let typed = matches!(value.trim(), "true" | "false")
+            || value.trim_start().starts_with(['"', '\'', '[', '{']);
+        let value = if typed {
+            value
+                .parse::<Value>()
+                .map_err(|e| anyhow!("invalid TOML value in {:?}: {}", arg, e))?
+        } else {
+            Value::from(value)
+        };
+        Ok(Self { key, value })
+    }
+
+    /// Set the field in `doc`, keeping the formatting of an existing value
+    fn apply(&self, doc: &mut DocumentMut) -> Result<()> {
+        let (last, parents) = self.key.split_last().expect("empty key");
+        let mut item = doc.as_item_mut();
+        for part in parents {
+            let inline = item.is_inline_table();
+            let table = item
+                .as_table_like_mut()
+                .with_context(|| format!("{:?} is not in a table", part))?;
+            if table.get(part).is_none() {
+                let new = if inline {
+                    Item::Value(Value::InlineTable(InlineTable::new()))
+                } else {
+                    let mut t = Table::new();
+                    t.set_implicit(true);
+                    Item::Table(t)
+                };
+                table.insert(part, new);
+            }
+            item = table.get_mut(part).expect("inserted above");
+        }
+        let inline = item.is_inline_table();
+        let table = item
+            .as_table_like_mut()
+            .with_context(|| format!("{:?} is not in a table", last))?;
+
+        let mut value = self.value.clone();
+        match table.get_mut(last) {
+            Some(Item::Value(old)) => {
+                *value.decor_mut() = old.decor().clone();
+                *old = value;
+            }
+            Some(_) => bail!("{:?} is a table, not a value", self.key.join(".")),
+            None => {
+                table.insert(last, Item::Value(value));
+                if inline {
+                    // New keys don't get spacing of their own
+                    item.as_inline_table_mut().expect("inline table").fmt();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Apply `updates` to the `Cargo.toml` in `dir`.
+/// Returns the old and new text if the manifest changed.
+/// The file is written only if `write` is set.
+pub fn update_manifest(
+    dir: &Path,
+    updates: &[FieldUpdate],
+    write: bool,
+) -> Result<Option<(String, String)>> {
+    let path = dir.join("Cargo.toml");
+    let old = fs::read_to_string(&path).with_context(|| format!("reading {:?}", path))?;
+    let mut doc: DocumentMut = old.parse().with_context(|| format!("parsing {:?}", path))?;
+    for update in updates {
+        update
+            .apply(&mut doc)
+            .with_context(|| format!("setting {} in {:?}", update.key.join("."), path))?;
+    }
+    let new = doc.to_string();
+    if new == old {
+        return Ok(None);
+    }
+    if write {
+        fs::write(&path, &new).with_context(|| format!("writing {:?}", path))?;
+    }
+    Ok(Some((old, new)))
+}
+
+/// Lines of context around changes in `unified_diff`
+const CONTEXT: usize = 3;
+
+/// Line based diff in the unified format
+pub fn unified_diff(name: &str, old: &str, new: &str) -> String {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+
+    // Longest common subsequence lengths of the suffixes
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    // Edit script as (tag, old index, new index, line)
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            ops.push((' ', i, j, a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ops.push(('-', i, j, a[i]));
+            i += 1;
+        } else {
+            ops.push(('+', i, j, b[j]));
+            j += 1;
+        }
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", name, name);
+    let mut k = 0;
+    while k < ops.len() {
+        if ops[k].0 == ' ' {
+            k += 1;
+            continue;
+        }
+        // Extend the hunk until there's enough unchanged lines to end it
+        let start = k.saturating_sub(CONTEXT);
+        let mut end = k;
+        let mut unchanged = 0;
+        while end < ops.len() && unchanged <= 2 * CONTEXT {
+            if ops[end].0 == ' ' {
+                unchanged += 1;
+            } else {
+                unchanged = 0;
+            }
+            end += 1;
+        }
+        let end = (end - unchanged + CONTEXT).min(ops.len());
+
+        let hunk = &ops[start..end];
+        let old_len = hunk.iter().filter(|op| op.0 != '+').count();
+        let new_len = hunk.iter().filter(|op| op.0 != '-').count();
+        out += &format!(
+            "@@ -{},{} +{},{} @@\n",
+            hunk[0].1 + 1,
+            old_len,
+            hunk[0].2 + 1,
+            new_len
+        );
+        for (tag, _, _, line) in hunk {
+            out += &format!("{}{}\n", tag, line);
+        }
+        k = end;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(arg: &str) -> String {
+        FieldUpdate::parse(arg).unwrap().value.to_string()
+    }
+
+    #[test]
+    fn parse_keeps_plain_values_as_strings() {
+        assert_eq!(parsed("package.version=1.0"), r#""1.0""#);
+        assert_eq!(parsed("package.rust-version=1.70"), r#""1.70""#);
+        assert_eq!(parsed("package.version=0.2.0"), r#""0.2.0""#);
+        assert_eq!(parsed("package.edition=2021"), r#""2021""#);
+    }
+
+    #[test]
+    fn parse_reads_typed_values() {
+        assert_eq!(parsed("package.publish=false"), "false");
+        assert_eq!(parsed(r#"package.name="foo""#), r#""foo""#);
+        assert_eq!(parsed(r#"package.keywords=["a", "b"]"#), r#"["a", "b"]"#);
+        assert_eq!(
+            parsed("dependencies.foo={ version = \"1\" }"),
+            r#"{ version = "1" }"#
+        );
+    }
+
+    #[test]
+    fn parse_splits_key() {
+        let update = FieldUpdate::parse("package.metadata.x=1").unwrap();
+        assert_eq!(update.key, ["package", "metadata", "x"]);
+        assert!(FieldUpdate::parse("version").is_err());
+        assert!(FieldUpdate::parse("package..version=1").is_err());
+        assert!(FieldUpdate::parse("package.publish=[false").is_err());
+    }
+
+    #[test]
+    fn diff_of_identical_text_has_no_hunks() {
+        assert_eq!(unified_diff("a", "x\ny\n", "x\ny\n"), "--- a\n+++ a\n");
+    }
+
+    #[test]
+    fn diff_shows_change_with_context() {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let new = "1\n2\n3\n4\nfive\n6\n7\n8\n9\n";
+        assert_eq!(
+            unified_diff("Cargo.toml", old, new),
+            "--- Cargo.toml\n+++ Cargo.toml\n@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n"
+        );
+    }
+
+    #[test]
+    fn diff_separates_distant_changes_into_hunks() {
+        let old: String = (1..=20).map(|i| format!("{}\n", i)).collect();
+        let new = old
+            .replacen("2\n", "two\n", 1)
+            .replace("19\n", "nineteen\n");
+        let diff = unified_diff("f", &old, &new);
+        assert_eq!(diff.matches("@@ -").count(), 2);
+        assert!(diff.contains("@@ -1,5 +1,5 @@\n 1\n-2\n+two\n"));
+        assert!(diff.contains("@@ -16,5 +16,5 @@\n"));
+    }
+
+    #[test]
+    fn update_applies_fields_and_keeps_formatting() {
+        let mut doc: DocumentMut = "[package]\nname = \"a\" # name\nversion = \"0.1.0\"\n"
+            .parse()
+            .unwrap();
+        FieldUpdate::parse("package.name=b")
+            .unwrap()
+            .apply(&mut doc)
+            .unwrap();
+        FieldUpdate::parse("package.publish=false")
+            .unwrap()
+            .apply(&mut doc)
+            .unwrap();
+        assert_eq!(
+            doc.to_string(),
+            "[package]\nname = \"b\" # name\nversion = \"0.1.0\"\npublish = false\n"
+        );
+    }
+}
@@ -0,0 +1,407 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::artifacts::format_size;
+use crate::json;
+use crate::memory::MemoryUsage;
+
+/// How running the command in a directory went
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Success,
+    /// The command returned a nonzero exit code, if any
+    Failed(Option<i32>),
+    /// The command couldn't be run at all
+    Error,
+}
+impl Status {
+    pub fn describe(self) -> String {
+        match self {
+            Status::Success => "ok".to_owned(),
+            Status::Failed(Some(code)) => format!("failed ({})", code),
+            Status::Failed(None) => "failed".to_owned(),
+            Status::Error => "error".to_owned(),
+        }
+    }
+
+    /// Exit code for JSON output, null if the command didn't return one
+    pub fn exit_code_json(self) -> json::Value {
+        match self {
+            Status::Failed(Some(code)) => json::Value::Number(code.into()),
+            Status::Success => json::Value::Number(0.0),
+            _ => json::Value::Null,
+        }
+    }
+}
+
+/// A single run of the command
+#[derive(Debug)]
+pub struct Entry {
+    pub dir: PathBuf,
+    pub target: Option<String>,
+    /// Comma separated features enabled by `--feature-powerset`
+    pub features: Option<String>,
+    pub status: Status,
+    /// Summary of `git diff` against the `--diff-against` reference, if requested
+    pub diff: Option<String>,
+    /// How long the command took, if measured
+    pub duration: Option<Duration>,
+    /// Output of the `--on-error-run` command, if the run failed
+    pub error_output: Option<String>,
+    /// Peak memory usage of the command, if measured
+    pub memory: Option<MemoryUsage>,
+}
+impl Entry {
+    /// Column of the summary table, for runs with a target or features
+    fn column(&self) -> Option<String> {
+        let features = self.features.as_ref().map(|f| format!("[{}]", f));
+        match (&self.target, features) {
+            (Some(t), Some(f)) => Some(format!("{} {}", t, f)),
+            (Some(t), None) => Some(t.clone()),
+            (None, f) => f,
+        }
+    }
+}
+
+/// Results of all runs, in the order they were made
+#[derive(Debug)]
+pub struct Report {
+    /// Search root, paths are shown relative to this
+    pub root: PathBuf,
+    pub entries: Vec<Entry>,
+    /// Size of the `target` directory of each crate, if measured
+    pub disk_usage: Vec<(PathBuf, u64)>,
+    /// Wall-clock time of the whole run, if measured
+    pub elapsed: Option<Duration>,
+}
+impl Report {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_owned(),
+            entries: Vec::new(),
+            disk_usage: Vec::new(),
+            elapsed: None,
+        }
+    }
+
+    /// Measure the size of the `target` directory of each of `dirs`
+    pub fn measure_disk_usage(&mut self, dirs: &[PathBuf]) {
+        for dir in dirs {
+            let target = dir.join("target");
+            if target.is_dir() {
+                self.disk_usage.push((dir.clone(), dir_size(&target)));
+            }
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        dir: &Path,
+        target: Option<&str>,
+        features: Option<&str>,
+        status: Status,
+    ) {
+        self.entries.push(Entry {
+            dir: dir.to_owned(),
+            target: target.map(str::to_owned),
+            features: features.map(str::to_owned),
+            status,
+            diff: None,
+            duration: None,
+            error_output: None,
+            memory: None,
+        });
+    }
+
+    /// Attach the duration of the command to the latest entry
+    pub fn set_duration(&mut self, duration: Duration) {
+        if let Some(e) = self.entries.last_mut() {
+            e.duration = Some(duration);
+        }
+    }
+
+    /// Attach the peak memory usage of the command to the latest entry
+    pub fn set_memory(&mut self, memory: MemoryUsage) {
+        if let Some(e) = self.entries.last_mut() {
+            e.memory = Some(memory);
+        }
+    }
+
+    /// Attach the output of the `--on-error-run` command to the latest entry
+    pub fn set_error_output(&mut self, output: String) {
+        if let Some(e) = self.entries.last_mut() {
+            e.error_output = Some(output);
+        }
+    }
+
+    /// Attach a diff summary to the latest entry
+    pub fn set_diff(&mut self, summary: String) {
+        if let Some(e) = self.entries.last_mut() {
+            e.diff = Some(summary);
+        }
+    }
+
+    /// Path of `dir` relative to the root, for display
+    pub fn display_path(&self, dir: &Path) -> String {
+        relative_path(&self.root, dir)
+    }
+
+    /// Print a status table to stderr, with a column for each target and feature set if any were used
+    pub fn print_summary(&self) {
+        if !self.entries.is_empty() {
+            self.print_status_table();
+        }
+        if !self.disk_usage.is_empty() {
+            self.print_disk_usage();
+        }
+        if self.entries.iter().any(|e| e.memory.is_some()) {
+            self.print_memory_usage();
+        }
+        if let Some(elapsed) = self.elapsed {
+            self.print_durations(elapsed);
+        }
+    }
+
+    fn print_durations(&self, elapsed: Duration) {
+        eprintln!("Total time: {}", format_duration(elapsed));
+        let timed = self.entries.iter().filter_map(|e| Some((e, e.duration?)));
+        let fastest = timed.clone().min_by_key(|(_, d)| *d);
+        let slowest = timed.max_by_key(|(_, d)| *d);
+        for (label, entry) in [("Fastest", fastest), ("Slowest", slowest)] {
+            if let Some((e, duration)) = entry {
+                let mut name = self.display_path(&e.dir);
+                if let Some(column) = e.column() {
+                    name = format!("{} ({})", name, column);
+                }
+                eprintln!("{}: {} {}", label, name, format_duration(duration));
+            }
+        }
+    }
+
+    fn print_disk_usage(&self) {
+        let mut usage: Vec<(String, u64)> = self
+            .disk_usage
+            .iter()
+            .map(|(dir, size)| (self.display_path(dir), *size))
+            .collect();
+        usage.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+
+        let width = usage.iter().map(|(n, _)| n.len()).max().unwrap_or(0).max(5);
+        eprintln!("Disk usage of target directories:");
+        for (name, size) in &usage {
+            eprintln!("  {:<w$}  {:>10}", name, format_size(*size), w = width);
+        }
+        let total = usage.iter().map(|(_, size)| size).sum();
+        eprintln!("  {:<w$}  {:>10}", "total", format_size(total), w = width);
+    }
+
+    fn print_memory_usage(&self) {
+        let rows: Vec<(String, MemoryUsage)> = self
+            .entries
+            .iter()
+            .filter_map(|e| {
+                let mut name = self.display_path(&e.dir);
+                if let Some(column) = e.column() {
+                    name = format!("{} ({})", name, column);
+                }
+                Some((name, e.memory?))
+            })
+            .collect();
+
+        let width = rows.iter().map(|(n, _)| n.len()).max().unwrap_or(0).max(7);
+        eprintln!("Peak memory usage of the largest process:");
+        eprintln!(
+            "  {:<w$}  {:>10}  {:>10}",
+            "project",
+            "virtual",
+            "rss",
+            w = width
+        );
+        for (name, memory) in &rows {
+            eprintln!(
+                "  {:<w$}  {:>10}  {:>10}",
+                name,
+                format_size(memory.virtual_peak),
+                format_size(memory.rss_peak),
+                w = width
+            );
+        }
+    }
+
+    fn print_status_table(&self) {
+        let mut dirs: Vec<&Path> = Vec::new();
+        let mut columns: Vec<Option<String>> = Vec::new();
+        for e in &self.entries {
+            if !dirs.contains(&e.dir.as_path()) {
+                dirs.push(&e.dir);
+            }
+            let column = e.column();
+            if !columns.contains(&column) {
+                columns.push(column);
+            }
+        }
+
+        let names: Vec<String> = dirs.iter().map(|d| self.display_path(d)).collect();
+        let first_width = names.iter().map(|n| n.len()).max().unwrap_or(0).max(7);
+        let widths: Vec<usize> = columns
+            .iter()
+            .map(|c| c.as_deref().unwrap_or("status").len().max(11))
+            .collect();
+
+        eprintln!("Summary:");
+        let mut header = format!("  {:<w$}", "project", w = first_width);
+        for (c, w) in columns.iter().zip(&widths) {
+            header += &format!("  {:<w$}", c.as_deref().unwrap_or("status"), w = w);
+        }
+        eprintln!("{}", header.trim_end());
+        for (dir, name) in dirs.iter().zip(&names) {
+            let mut row = format!("  {:<w$}", name, w = first_width);
+            for (c, w) in columns.iter().zip(&widths) {
+                let status = self
+                    .entries
+                    .iter()
+                    .find(|e| e.dir == *dir && e.column() == *c)
+                    .map(|e| e.status.describe())
+                    .unwrap_or_else(|| "-".to_owned());
+                row += &format!("  {:<w$}", status, w = w);
+            }
+            eprintln!("{}", row.trim_end());
+        }
+
+        let failed = self
+            .entries
+            .iter()
+            .filter(|e| e.status != Status::Success)
+            .count();
+        eprintln!(
+            "{} runs, {} succeeded, {} failed",
+            self.entries.len(),
+            self.entries.len() - failed,
+            failed
+        );
+    }
+}
+
+impl Report {
+    pub fn to_json(&self) -> json::Value {
+        json::Value::object(vec![
+            ("root", self.root.to_string_lossy().into_owned().into()),
+            (
+                "runs",
+                json::Value::Array(
+                    self.entries
+                        .iter()
+                        .map(|e| {
+                            json::Value::object(vec![
+                                ("path", e.dir.to_string_lossy().into_owned().into()),
+                                ("target", e.target.clone().into()),
+                                ("features", e.features.clone().into()),
+                                ("success", (e.status == Status::Success).into()),
+                                ("status", e.status.describe().into()),
+                                ("exit_code", e.status.exit_code_json()),
+                                ("diff", e.diff.clone().into()),
+                                (
+                                    "duration_secs",
+                                    e.duration
+                                        .map(|d| json::Value::Number(d.as_secs_f64()))
+                                        .unwrap_or(json::Value::Null),
+                                ),
+                                ("on_error_output", e.error_output.clone().into()),
+                                (
+                                    "peak_virtual_memory",
+                                    e.memory.map(|m| m.virtual_peak).into(),
+                                ),
+                                ("peak_rss", e.memory.map(|m| m.rss_peak).into()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "disk_usage",
+                json::Value::Array(
+                    self.disk_usage
+                        .iter()
+                        .map(|(dir, size)| {
+                            json::Value::object(vec![
+                                ("path", dir.to_string_lossy().into_owned().into()),
+                                ("target_dir_size", (*size).into()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "elapsed_secs",
+                self.elapsed
+                    .map(|d| json::Value::Number(d.as_secs_f64()))
+                    .unwrap_or(json::Value::Null),
+            ),
+        ])
+    }
+
+    /// Write the report as JSON to `path`
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        fs::write(path, format!("{}\n", self.to_json()))
+            .with_context(|| format!("writing report to {:?}", path))
+    }
+}
+
+/// Read a report written by `Report::write_json`, returning the paths of failed runs
+pub fn read_failed(path: &Path) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path).with_context(|| format!("reading report {:?}", path))?;
+    let report = json::parse(&text).with_context(|| format!("parsing report {:?}", path))?;
+    let runs = report
+        .get("runs")
+        .and_then(json::Value::as_array)
+        .with_context(|| format!("report {:?} has no runs", path))?;
+
+    let mut failed: Vec<String> = Vec::new();
+    for run in runs {
+        if run.get("success") == Some(&json::Value::Bool(false)) {
+            if let Some(path) = run.get("path").and_then(json::Value::as_str) {
+                if !failed.iter().any(|p| p == path) {
+                    failed.push(path.to_owned());
+                }
+            }
+        }
+    }
+    Ok(failed)
+}
+
+/// Total size of the files in a directory, symlinks aren't followed
+fn dir_size(path: &Path) -> u64 {
+    let mut total = 0;
+    if let Ok(entries) = path.read_dir() {
+        for e in entries.flatten() {
+            match e.file_type() {
+                Ok(t) if t.is_dir() => total += dir_size(&e.path()),
+                Ok(_) => total += e.metadata().map(|m| m.len()).unwrap_or(0),
+                Err(_) => {}
+            }
+        }
+    }
+    total
+}
+
+/// Duration for display, e.g. `1.25s` or `2m 03s`
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{:.2}s", duration.as_secs_f64())
+    } else {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    }
+}
+
+/// Path of `dir` relative to `root` for display, or the full path if not under it
+pub fn relative_path(root: &Path, dir: &Path) -> String {
+    match dir.strip_prefix(root) {
+        Ok(p) if p.as_os_str().is_empty() => ".".to_owned(),
+        Ok(p) => p.display().to_string(),
+        Err(_) => dir.display().to_string(),
+    }
+}
@@ -0,0 +1,760 @@
+use std::env;
+use std::fs::{self, File, TryLockError};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::config;
+use crate::diagnostics;
+use crate::json;
+use crate::manifest::Manifest;
+
+/// Outcome of a single rule of the decision pipeline
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    /// The rule doesn't object to this directory
+    Pass,
+    /// Don't run in this directory, but keep searching its subdirectories
+    Skip,
+    /// Don't run in this directory or search any of its subdirectories
+    Prune,
+}
+impl Verdict {
+    fn as_str(self) -> &'static str {
+        match self {
+            Verdict::Pass => "pass",
+            Verdict::Skip => "skip",
+            Verdict::Prune => "prune",
+        }
+    }
+}
+
+/// A rule of the decision pipeline, and what it said about a directory
+#[derive(Debug)]
+pub struct Check {
+    pub rule: String,
+    pub verdict: Verdict,
+}
+impl Check {
+    fn new(verdict: Verdict, rule: String) -> Self {
+        Self { rule, verdict }
+    }
+}
+
+/// Order of the directories returned by `Scanner::scan`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortBy {
+    /// Order in which the directories were found
+    Discovery,
+    /// Alphabetical by directory name
+    Name,
+    /// Alphabetical by full path
+    Path,
+    /// Shallowest first
+    Depth,
+    /// Most recently modified first
+    ModTime,
+}
+
+/// Directories found by `Scanner::scan`
+#[derive(Debug, Default)]
+pub struct Scan {
+    /// Directories to run the command in
+    pub dirs: Vec<PathBuf>,
+    /// Directories skipped for non-obvious reasons and the rule that skipped them,
+    /// only collected when verbose so that the caller can log them
+    pub skipped: Vec<(PathBuf, String)>,
+}
+impl Scan {
+    /// Log the skipped directories with `note`, returning the directories to run in
+    pub fn into_dirs(self, mut note: impl FnMut(&str)) -> Vec<PathBuf> {
+        for (dir, rule) in &self.skipped {
+            note(&format!("Skipping {:?}: {}", dir, rule));
+        }
+        self.dirs
+    }
+}
+
+/// Decides which directories the command is run in
+#[derive(Debug)]
+pub struct Scanner {
+    /// Directory the search starts from
+    pub root: PathBuf,
+    /// Max depth to search into, the root being at depth zero
+    pub max_depth: usize,
+    /// Added to the depth before comparing it to `max_depth`,
+    /// the depth of the root below the workspace root with `--depth-from-workspace-root`
+    pub depth_offset: usize,
+    /// Don't search directories named `examples`
+    pub skip_examples: bool,
+    /// Don't search directories named `benches`
+    pub skip_benches: bool,
+    /// Glob patterns of directory names or paths relative to the root not to search
+    pub ignore: Vec<String>,
+    /// Glob patterns of directory names or paths relative to the root to run in, if any are given
+    pub include: Vec<String>,
+    /// Skip crates containing a file with any of these names
+    pub exclude_if_file_exists: Vec<String>,
+    /// Only run in crates containing a file with one of these names, if any are given
+    pub include_if_file_exists: Vec<String>,
+    /// Skip crates without a `Cargo.lock` in their workspace
+    pub require_cargo_lock: bool,
+    /// Skip crates whose target directory is locked by a running cargo
+    pub skip_building: bool,
+    /// Program deciding whether to run in a crate, see `run_filter_script`
+    pub filter_script: Option<String>,
+    /// Skip crates where `cargo check` reports no errors or warnings
+    pub skip_if_check_clean: bool,
+    /// Only run in crates that are (`true`) or aren't (`false`) published to crates.io
+    pub crates_io: Option<bool>,
+    /// Only run in crates that can (`true`) or can't (`false`) be published to any registry
+    pub publishable: Option<bool>,
+    /// Don't search outside this git repository, canonicalized
+    pub git_root: Option<PathBuf>,
+    /// Search symlinked directories too
+    pub follow_symlinks: bool,
+    /// Shell command listing the directories, one per line, used instead of walking the tree
+    pub resolver: Option<String>,
+    /// Order of the results
+    pub sort_by: SortBy,
+    /// Reverse the order of the results
+    pub reverse: bool,
+    /// Leave out this many directories from the start
+    pub offset: usize,
+    /// Only return this many directories, the first ones in order after `offset`
+    pub limit: Option<usize>,
+    /// Log directories that are skipped for non-obvious reasons
+    pub verbose: bool,
+}
+impl Scanner {
+    /// Whether any rule needs to look at the contents of the manifest
+    fn needs_manifest(&self) -> bool {
+        self.crates_io.is_some() || self.publishable.is_some()
+    }
+
+    /// Evaluate the decision pipeline for `path`, which is `depth` levels below the root.
+    /// Stops at the first rule that doesn't pass, so the last check is the decisive one.
+    pub fn evaluate(&self, path: &Path, depth: usize) -> Vec<Check> {
+        let mut checks = Vec::new();
+        let _ = self.run_rules(path, depth, false, &mut checks);
+        checks
+    }
+
+    /// Evaluate the decision pipeline for a directory listed by the external resolver,
+    /// which isn't limited by the depth
+    pub fn evaluate_resolved(&self, path: &Path) -> Vec<Check> {
+        let depth = match (absolute(&self.root), absolute(path)) {
+            (Ok(root), Ok(path)) => path
+                .strip_prefix(root)
+                .map_or(1, |relative| relative.components().count()),
+            _ => 1,
+        };
+        let mut checks = Vec::new();
+        let _ = self.run_rules(path, depth, true, &mut checks);
+        checks
+    }
+
+    /// The rules in evaluation order, returns `None` at the first one that doesn't pass.
+    /// Directories `resolved` by the external resolver aren't limited by the depth.
+    fn run_rules(
+        &self,
+        path: &Path,
+        depth: usize,
+        resolved: bool,
+        checks: &mut Vec<Check>,
+    ) -> Option<()> {
+        let mut check = |verdict, rule: String| {
+            checks.push(Check::new(verdict, rule));
+            if verdict == Verdict::Pass {
+                Some(())
+            } else {
+                None
+            }
+        };
+
+        if depth != 0 {
+            match path.symlink_metadata() {
+                Ok(m) if m.file_type().is_symlink() && !self.follow_symlinks => check(
+                    Verdict::Prune,
+                    "symlink, not followed without --follow-symlinks".to_owned(),
+                )?,
+                Ok(m) if m.file_type().is_symlink() => {
+                    if !path.is_dir() {
+                        check(Verdict::Prune, "symlink to a non-directory".to_owned())?;
+                    }
+                    let target = path.canonicalize().ok();
+                    let parent = path.parent().and_then(|p| p.canonicalize().ok());
+                    if let (Some(target), Some(parent)) = (target, parent) {
+                        if parent.starts_with(&target) {
+                            check(
+                                Verdict::Prune,
+                                format!("symlink to ancestor {:?}, would loop", target),
+                            )?;
+                        }
+                    }
+                    check(Verdict::Pass, "symlink followed".to_owned())?;
+                }
+                Ok(m) if !m.is_dir() => check(Verdict::Prune, "not a directory".to_owned())?,
+                Ok(_) => {}
+                Err(e) => check(Verdict::Prune, format!("cannot be accessed: {}", e))?,
+            }
+
+            if let Some(git_root) = &self.git_root {
+                if path.join(".git").exists() {
+                    check(
+                        Verdict::Prune,
+                        "separate git repository, skipped by --limit-to-git-root".to_owned(),
+                    )?;
+                }
+                if !path.canonicalize().is_ok_and(|p| p.starts_with(git_root)) {
+                    check(
+                        Verdict::Prune,
+                        format!("outside git repository {:?}", git_root),
+                    )?;
+                }
+            }
+
+            let name = path.file_name().unwrap_or_default();
+            if self.skip_examples && name == "examples" {
+                check(Verdict::Prune, "skipped by --skip-examples-dir".to_owned())?;
+            }
+            if self.skip_benches && name == "benches" {
+                check(Verdict::Prune, "skipped by --skip-benches-dir".to_owned())?;
+            }
+            let name = name.to_string_lossy();
+            let relative = path
+                .strip_prefix(&self.root)
+                .unwrap_or(path)
+                .to_string_lossy();
+            for pattern in &self.ignore {
+                if config::glob_match(pattern, &name) || config::glob_match(pattern, &relative) {
+                    check(
+                        Verdict::Prune,
+                        format!("matches {:?} in the global ignore file", pattern),
+                    )?;
+                }
+            }
+        }
+
+        if resolved {
+            check(Verdict::Pass, "listed by the external resolver".to_owned())?;
+        } else {
+            let depth = depth + self.depth_offset;
+            if depth >= self.max_depth {
+                check(
+                    Verdict::Prune,
+                    format!("beyond max depth ({}/{})", depth, self.max_depth),
+                )?;
+            }
+            check(
+                Verdict::Pass,
+                format!("within depth ({}/{})", depth, self.max_depth),
+            )?;
+        }
+
+        if !path.join("Cargo.toml").exists() {
+            check(Verdict::Skip, "no Cargo.toml".to_owned())?;
+        }
+        check(Verdict::Pass, "has Cargo.toml".to_owned())?;
+
+        if !self.include.is_empty() {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let relative = path
+                .strip_prefix(&self.root)
+                .unwrap_or(path)
+                .to_string_lossy();
+            match self.include.iter().find(|pattern| {
+                config::glob_match(pattern, &name) || config::glob_match(pattern, &relative)
+            }) {
+                Some(pattern) => check(
+                    Verdict::Pass,
+                    format!("matches {:?} in CARGO_RECURSIVE_INCLUDE", pattern),
+                )?,
+                None => check(
+                    Verdict::Skip,
+                    "matches no pattern in CARGO_RECURSIVE_INCLUDE".to_owned(),
+                )?,
+            }
+        }
+
+        for name in &self.exclude_if_file_exists {
+            if path.join(name).exists() {
+                check(
+                    Verdict::Skip,
+                    format!("has {:?}, skipped by --exclude-if-file-exists", name),
+                )?;
+            }
+        }
+        if !self.include_if_file_exists.is_empty() {
+            match self
+                .include_if_file_exists
+                .iter()
+                .find(|name| path.join(name).exists())
+            {
+                Some(name) => check(Verdict::Pass, format!("has {:?}", name))?,
+                None => check(
+                    Verdict::Skip,
+                    "no file required by --include-if-file-exists".to_owned(),
+                )?,
+            }
+        }
+
+        if self.needs_manifest() {
+            let manifest = match Manifest::read(path) {
+                Ok(manifest) => manifest,
+                Err(e) => return check(Verdict::Skip, format!("manifest can't be read: {:#}", e)),
+            };
+            match manifest.name() {
+                Some(name) => check(
+                    Verdict::Pass,
+                    format!("manifest parsed, package name '{}'", name),
+                )?,
+                None => check(
+                    Verdict::Pass,
+                    "manifest parsed, virtual manifest".to_owned(),
+                )?,
+            }
+
+            if let Some(only) = self.crates_io {
+                let published = manifest.publishes_to_crates_io();
+                match (only, published) {
+                    (true, false) => check(
+                        Verdict::Skip,
+                        "not published to crates.io, skipped by --only-crates-io".to_owned(),
+                    )?,
+                    (false, true) => check(
+                        Verdict::Skip,
+                        "published to crates.io, skipped by --exclude-crates-io".to_owned(),
+                    )?,
+                    (_, published) => check(
+                        Verdict::Pass,
+                        format!(
+                            "{}published to crates.io",
+                            if published { "" } else { "not " }
+                        ),
+                    )?,
+                }
+            }
+
+            if let Some(only) = self.publishable {
+                match (only, manifest.publishable()) {
+                    (true, false) => check(
+                        Verdict::Skip,
+                        "publishing disabled, skipped by --skip-unpublishable".to_owned(),
+                    )?,
+                    (false, true) => check(
+                        Verdict::Skip,
+                        "publishable, skipped by --only-unpublishable".to_owned(),
+                    )?,
+                    (_, publishable) => check(
+                        Verdict::Pass,
+                        format!(
+                            "publishing {}",
+                            if publishable { "allowed" } else { "disabled" }
+                        ),
+                    )?,
+                }
+            }
+        }
+
+        if self.require_cargo_lock {
+            match has_cargo_lock(path) {
+                Ok(true) => check(Verdict::Pass, "has Cargo.lock".to_owned())?,
+                Ok(false) => check(
+                    Verdict::Skip,
+                    "no Cargo.lock, skipped by --require-cargo-lock".to_owned(),
+                )?,
+                Err(e) => check(Verdict::Skip, format!("workspace can't be read: {:#}", e))?,
+            }
+        }
+
+        if self.skip_building {
+            match is_building(path) {
+                Ok(false) => check(Verdict::Pass, "no build in progress".to_owned())?,
+                Ok(true) => check(
+                    Verdict::Skip,
+                    "build in progress, skipped by --skip-currently-building".to_owned(),
+                )?,
+                Err(e) => check(Verdict::Pass, format!("build locks can't be read: {:#}", e))?,
+            }
+        }
+
+        if let Some(script) = &self.filter_script {
+            match run_filter_script(script, path) {
+                Ok(true) => check(Verdict::Pass, format!("accepted by {:?}", script))?,
+                Ok(false) => check(Verdict::Skip, format!("rejected by {:?}", script))?,
+                Err(e) => check(Verdict::Skip, format!("filter script failed: {:#}", e))?,
+            }
+        }
+
+        if self.skip_if_check_clean {
+            match check_diagnostics(path) {
+                Ok(0) => check(Verdict::Skip, "cargo check is clean".to_owned())?,
+                Ok(n) => check(
+                    Verdict::Pass,
+                    format!("cargo check reported {} diagnostics", n),
+                )?,
+                Err(e) => check(Verdict::Pass, format!("cargo check failed: {}", e))?,
+            }
+        }
+
+        Some(())
+    }
+
+    /// Walk the tree, returning the directories to run the command in.
+    /// Errors from reading subdirectories are only warnings unless `exit_on_error` is set.
+    pub fn scan(&self, exit_on_error: bool) -> Result<Scan> {
+        let mut scan = Scan::default();
+        match &self.resolver {
+            Some(command) => self.resolve(command, exit_on_error, &mut scan)?,
+            None => self.walk(&self.root, 0, exit_on_error, &mut scan)?,
+        }
+        let result = &mut scan.dirs;
+        match self.sort_by {
+            SortBy::Discovery => {}
+            SortBy::Name => result.sort_by(|a, b| (a.file_name(), a).cmp(&(b.file_name(), b))),
+            SortBy::Path => result.sort(),
+            SortBy::Depth => result.sort_by_key(|p| p.components().count()),
+            SortBy::ModTime => result.sort_by_cached_key(|p| {
+                std::cmp::Reverse(p.metadata().and_then(|m| m.modified()).ok())
+            }),
+        }
+        if self.reverse {
+            result.reverse();
+        }
+        result.drain(..self.offset.min(result.len()));
+        if let Some(limit) = self.limit {
+            result.truncate(limit);
+        }
+        Ok(scan)
+    }
+
+    /// Lines of the output of `command`, run in the root
+    fn run_resolver(&self, command: &str) -> Result<Vec<String>> {
+        let output = crate::shell_command(command)
+            .current_dir(&self.root)
+            .stderr(Stdio::inherit())
+            .output()
+            .with_context(|| format!("running resolver {:?}", command))?;
+        if !output.status.success() {
+            bail!("resolver {:?} failed with {}", command, output.status);
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_owned)
+            .collect())
+    }
+
+    /// Read the directories from the output of `command`, keeping the ones that pass
+    /// the decision pipeline. Relative paths are relative to the root.
+    fn resolve(&self, command: &str, exit_on_error: bool, scan: &mut Scan) -> Result<()> {
+        for line in self.run_resolver(command)? {
+            let dir = self.root.join(&line);
+            if !dir.is_dir() {
+                let e = anyhow!("resolver listed {:?}, which is not a directory", line);
+                if exit_on_error {
+                    return Err(e);
+                }
+                crate::warn(&e);
+                continue;
+            }
+            let checks = self.evaluate_resolved(&dir);
+            match checks.last() {
+                Some(c) if c.verdict != Verdict::Pass => {
+                    if self.verbose {
+                        scan.skipped.push((dir, c.rule.clone()));
+                    }
+                }
+                _ => scan.dirs.push(dir),
+            }
+        }
+        Ok(())
+    }
+
+    fn walk(&self, path: &Path, depth: usize, exit_on_error: bool, scan: &mut Scan) -> Result<()> {
+        let checks = self.evaluate(path, depth);
+        match checks.last().map(|c| c.verdict) {
+            Some(Verdict::Prune) => {
+                let is_symlink = path
+                    .symlink_metadata()
+                    .is_ok_and(|m| m.file_type().is_symlink());
+                if self.verbose && is_symlink {
+                    let rule = &checks.last().expect("no checks").rule;
+                    scan.skipped.push((path.to_owned(), rule.clone()));
+                }
+                return Ok(());
+            }
+            Some(Verdict::Skip) => {}
+            _ => scan.dirs.push(path.to_owned()),
+        }
+
+        for e in path
+            .read_dir()
+            .with_context(|| format!("reading directory {:?}", path.canonicalize()))?
+        {
+            let e = e?;
+            if let Err(e) = self.walk(&e.path(), depth + 1, exit_on_error, scan) {
+                if exit_on_error {
+                    return Err(e);
+                }
+                crate::warn(&e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Trace the decisions made for `target` and all of its ancestors up to the root
+    pub fn explain(&self, target: &Path) -> Result<Explanation> {
+        let root = absolute(&self.root)?;
+        let target = absolute(target)?;
+        let mut explanation = Explanation {
+            path: target.clone(),
+            pruned_ancestor: None,
+            checks: Vec::new(),
+            resolved: self.resolver.is_some(),
+        };
+
+        let relative = match target.strip_prefix(&root) {
+            Ok(relative) => relative,
+            Err(_) => {
+                explanation.checks.push(Check::new(
+                    Verdict::Prune,
+                    format!("not under the search root {:?}", root),
+                ));
+                return Ok(explanation);
+            }
+        };
+
+        if let Some(command) = &self.resolver {
+            let mut listed = false;
+            for line in self.run_resolver(command)? {
+                listed |= absolute(&self.root.join(line))? == target;
+            }
+            if listed {
+                explanation.checks = self.evaluate_resolved(&target);
+            } else {
+                explanation.checks.push(Check::new(
+                    Verdict::Skip,
+                    "not listed by the external resolver".to_owned(),
+                ));
+            }
+            return Ok(explanation);
+        }
+
+        let mut current = root;
+        for (depth, component) in relative.components().enumerate() {
+            let checks = self.evaluate(&current, depth);
+            if checks.last().map(|c| c.verdict) == Some(Verdict::Prune) {
+                explanation.pruned_ancestor = Some((current, checks));
+                return Ok(explanation);
+            }
+            current.push(component);
+        }
+
+        explanation.checks = self.evaluate(&current, relative.components().count());
+        Ok(explanation)
+    }
+}
+
+/// Run `cargo check` quietly, returning the number of errors and warnings reported
+fn check_diagnostics(path: &Path) -> Result<usize> {
+    let output = Command::new("cargo")
+        .args(["check", "--quiet", "--message-format", "json"])
+        .current_dir(path)
+        .output()
+        .context("running cargo check")?;
+
+    let diagnostics = diagnostics::collect(&output.stdout);
+    let count = diagnostics.errors + diagnostics.warnings;
+
+    if count == 0 && !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(count)
+}
+
+/// Run `script` with the directory as its argument and the manifest as its stdin,
+/// returning whether it exited successfully
+fn run_filter_script(script: &str, path: &Path) -> Result<bool> {
+    let manifest = fs::read(path.join("Cargo.toml")).context("reading Cargo.toml")?;
+    let mut child = Command::new(script)
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .with_context(|| format!("running {:?}", script))?;
+    // The script may exit without reading its input
+    let _ = child.stdin.take().expect("piped").write_all(&manifest);
+    Ok(child.wait()?.success())
+}
+
+/// Why a directory would or wouldn't be processed
+#[derive(Debug)]
+pub struct Explanation {
+    pub path: PathBuf,
+    /// Ancestor that prevented the search from reaching the path, if any
+    pub pruned_ancestor: Option<(PathBuf, Vec<Check>)>,
+    /// Checks of the path itself, empty if an ancestor was pruned
+    pub checks: Vec<Check>,
+    /// The directories come from the external resolver, so subdirectories aren't searched
+    pub resolved: bool,
+}
+impl Explanation {
+    /// Final decision for the directory
+    pub fn verdict(&self) -> Verdict {
+        if self.pruned_ancestor.is_some() {
+            return Verdict::Prune;
+        }
+        self.checks
+            .last()
+            .map(|c| c.verdict)
+            .unwrap_or(Verdict::Pass)
+    }
+
+    pub fn print_text(&self) {
+        println!("Explaining {:?}", self.path);
+        if let Some((ancestor, checks)) = &self.pruned_ancestor {
+            println!("  ancestor {:?}", ancestor);
+            for c in checks {
+                println!("    [{}] {}", c.verdict.as_str(), c.rule);
+            }
+        }
+        for c in &self.checks {
+            println!("  [{}] {}", c.verdict.as_str(), c.rule);
+        }
+        println!(
+            "=> {}",
+            match self.verdict() {
+                Verdict::Pass => "the command would be run in this directory",
+                Verdict::Skip if self.resolved => "skipped",
+                Verdict::Skip => "skipped, but its subdirectories are searched",
+                Verdict::Prune => "not searched",
+            }
+        );
+    }
+
+    pub fn to_json(&self) -> json::Value {
+        fn checks_json(checks: &[Check]) -> json::Value {
+            json::Value::Array(
+                checks
+                    .iter()
+                    .map(|c| {
+                        json::Value::object(vec![
+                            ("rule", c.rule.as_str().into()),
+                            ("verdict", c.verdict.as_str().into()),
+                        ])
+                    })
+                    .collect(),
+            )
+        }
+
+        json::Value::object(vec![
+            ("path", self.path.to_string_lossy().into_owned().into()),
+            (
+                "pruned_ancestor",
+                match &self.pruned_ancestor {
+                    Some((path, checks)) => json::Value::object(vec![
+                        ("path", path.to_string_lossy().into_owned().into()),
+                        ("checks", checks_json(checks)),
+                    ]),
+                    None => json::Value::Null,
+                },
+            ),
+            ("checks", checks_json(&self.checks)),
+            ("verdict", self.verdict().as_str().into()),
+        ])
+    }
+}
+
+/// Find the root of the git repository containing `path`
+pub fn find_git_root(path: &Path) -> Result<PathBuf> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("resolving {:?}", path))?;
+    for dir in path.ancestors() {
+        if dir.join(".git").exists() {
+            return Ok(dir.to_owned());
+        }
+    }
+    bail!("{:?} is not inside a git repository", path);
+}
+
+/// Root of the workspace containing the crate in `dir`, the nearest directory
+/// with a `[workspace]` manifest, or `dir` itself if there isn't one
+pub fn find_workspace_root(dir: &Path) -> Result<PathBuf> {
+    let dir = absolute(dir)?;
+    for ancestor in dir.ancestors() {
+        if !ancestor.join("Cargo.toml").is_file() {
+            continue;
+        }
+        if Manifest::read(ancestor)?.get("workspace").is_some() {
+            return Ok(ancestor.to_owned());
+        }
+    }
+    Ok(dir)
+}
+
+/// Whether the workspace of the crate in `dir` has a `Cargo.lock`
+pub fn has_cargo_lock(dir: &Path) -> Result<bool> {
+    Ok(find_workspace_root(dir)?.join("Cargo.lock").exists())
+}
+
+/// Whether a cargo process holds a lock on the target directory of the crate in `dir`.
+/// Cargo locks `.cargo-lock` in the profile directories, e.g. `target/debug`,
+/// or `target/<triple>/debug` when building for a target.
+pub fn is_building(dir: &Path) -> Result<bool> {
+    let target = match env::var_os("CARGO_TARGET_DIR") {
+        Some(target) => PathBuf::from(target),
+        None => find_workspace_root(dir)?.join("target"),
+    };
+    if !target.is_dir() {
+        return Ok(false);
+    }
+    let mut profiles = Vec::new();
+    for e in target.read_dir()? {
+        let path = e?.path();
+        if path.is_dir() {
+            profiles.extend(path.read_dir()?.filter_map(|e| Some(e.ok()?.path())));
+            profiles.push(path);
+        }
+    }
+    for profile in profiles {
+        let lock = profile.join(".cargo-lock");
+        if !lock.is_file() {
+            continue;
+        }
+        let file = File::open(&lock).with_context(|| format!("opening {:?}", lock))?;
+        match file.try_lock_shared() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(true),
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("locking {:?}", lock))
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Make path absolute and lexically normalized, without resolving symlinks
+pub fn absolute(path: &Path) -> Result<PathBuf> {
+    let mut result = if path.is_absolute() {
+        PathBuf::new()
+    } else {
+        env::current_dir().context("getting current_dir")?
+    };
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            other => result.push(other),
+        }
+    }
+    Ok(result)
+}